@@ -1,8 +1,11 @@
 use super::app::Closer;
-use futures::{Future, future};
-use hyper::{self, Get, Post, StatusCode};
+use super::connector::ConnectorConfig;
+use super::reload::Reloader;
+use futures::{Future, Stream, future};
+use hyper::{self, Get, Post, Put, StatusCode};
 use hyper::header::ContentLength;
 use hyper::server::{Service, Request, Response};
+use serde_yaml;
 use std::boxed::Box;
 use std::cell::RefCell;
 use std::process;
@@ -10,12 +13,14 @@ use std::rc::Rc;
 use std::time::{Duration, Instant};
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
+use url::form_urlencoded;
 
 #[derive(Clone)]
 pub struct Admin {
     prometheus: Rc<RefCell<String>>,
-    closer: Rc<RefCell<Option<Closer>>>,
+    closer: Closer,
     grace: Duration,
+    reloader: Reloader,
     reactor: Handle,
     timer: Timer,
 }
@@ -27,13 +32,15 @@ impl Admin {
         prometheus: Rc<RefCell<String>>,
         closer: Closer,
         grace: Duration,
+        reloader: Reloader,
         reactor: Handle,
         timer: Timer,
     ) -> Admin {
         Admin {
-            closer: Rc::new(RefCell::new(Some(closer))),
+            closer,
             prometheus,
             grace,
+            reloader,
             reactor,
             timer,
         }
@@ -49,17 +56,60 @@ impl Admin {
     }
 
     /// Tell the serving thread to stop what it's doing.
-    // TODO offer a `force` param?
-    fn shutdown(&self) -> RspFuture {
-        let mut closer = self.closer.borrow_mut();
-        if let Some(c) = closer.take() {
+    ///
+    /// Active streams are given the grace period to complete unless `force` is set, in
+    /// which case they are closed immediately.
+    fn shutdown(&self, force: bool) -> RspFuture {
+        let deadline = if force {
+            info!("shutting down immediately via admin API");
+            Instant::now()
+        } else {
             info!("shutting down via admin API");
-            let _ = c.send(Instant::now() + self.grace);
-        }
+            Instant::now() + self.grace
+        };
+        let _ = self.closer.unbounded_send(deadline);
         let rsp = Response::new().with_status(StatusCode::Ok);
         Box::new(future::ok(rsp))
     }
 
+    /// Reloads the configuration file, responding with a summary of the applied changes
+    /// or with the error that prevented them from being applied.
+    fn reload_config(&self) -> RspFuture {
+        let reload = self.reloader.reload(&self.reactor, &self.timer);
+        let rsp = reload.then(|res| match res {
+            Ok(summary) => text_response(StatusCode::Ok, summary),
+            Err(e) => text_response(StatusCode::InternalServerError, e),
+        });
+        Box::new(rsp)
+    }
+
+    /// Replaces the configurations of the dynamic client of the router named by the
+    /// `router` query parameter with the YAML or JSON list in the request body.
+    fn update_client_config(&self, req: Request) -> RspFuture {
+        let router = match query_param(req.query(), "router") {
+            Some(router) => router,
+            None => return text_response(StatusCode::BadRequest, "router must be specified".into()),
+        };
+        let reloader = self.reloader.clone();
+        let rsp = req.body().concat2().and_then(move |body| -> RspFuture {
+            let configs = match serde_yaml::from_slice::<Vec<ConnectorConfig>>(&body) {
+                Ok(configs) => configs,
+                Err(e) => {
+                    let msg = format!("invalid client configuration: {}", e);
+                    return text_response(StatusCode::BadRequest, msg);
+                }
+            };
+            let rsp = reloader.update_client_config(router, configs).then(|res| {
+                match res {
+                    Ok(summary) => text_response(StatusCode::Ok, summary),
+                    Err(e) => text_response(StatusCode::BadRequest, e),
+                }
+            });
+            Box::new(rsp)
+        });
+        Box::new(rsp)
+    }
+
     fn abort(&self) -> RspFuture {
         process::exit(1);
     }
@@ -76,11 +126,37 @@ impl Service for Admin {
     type Error = hyper::Error;
     type Future = RspFuture;
     fn call(&self, req: Request) -> RspFuture {
+        if *req.method() == Put && req.path() == "/client-config" {
+            return self.update_client_config(req);
+        }
         match (req.method(), req.path()) {
             (&Get, "/metrics") => self.metrics(),
-            (&Post, "/shutdown") => self.shutdown(),
+            (&Post, "/shutdown") => self.shutdown(is_forced(req.query())),
+            (&Post, "/config/reload") => self.reload_config(),
             (&Post, "/abort") => self.abort(),
             _ => self.not_found(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Determines whether a shutdown request should skip the grace period.
+fn is_forced(query: Option<&str>) -> bool {
+    query_param(query, "force").map(|v| v == "true").unwrap_or(false)
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query.and_then(|q| {
+        form_urlencoded::parse(q.as_bytes())
+            .find(|&(ref k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    })
+}
+
+fn text_response(status: StatusCode, body: String) -> RspFuture {
+    let body = format!("{}\n", body);
+    let rsp = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body);
+    Box::new(future::ok(rsp))
+}
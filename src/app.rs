@@ -1,18 +1,21 @@
 //! Provides all of the utilities needed to load a configuration and run a process.
 
-use super::{admin, resolver, router, server};
+use super::{admin, handoff, reload, resolver, router, server, signals};
 use super::balancer::BalancerFactory;
-use super::connector::{ConfigError as ConnectorConfigError, ConnectorFactoryConfig};
+use super::connector::{ConfigError as ConnectorConfigError, ConnectorConfig,
+                       ConnectorFactory, ConnectorFactoryConfig};
 use super::resolver::{ConfigError as ResolverConfigError, NamerdConfig};
 use super::server::ConfigError as ServerConfigError;
-use futures::{Future, Stream, sync};
+use futures::{Future, Stream, future, sync, unsync};
 use hyper;
 use hyper::server::Http;
 use serde_json;
 use serde_yaml;
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::net;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::{io, net};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 use tacho;
@@ -45,17 +48,34 @@ pub enum Error {
 
     /// Indicats a misconfigured server.
     Server(ServerConfigError),
+
+    /// Indicates that a server, or the admin server, could not listen on its configured
+    /// address.
+    Bind(net::SocketAddr, io::Error),
+
+    /// Indicates that more than one router has the same label.
+    DuplicateRouter(String),
+
+    /// Indicates that more than one server is configured on the same address.
+    DuplicateServer(net::SocketAddr),
 }
 
+pub use super::handoff::{Handoff, Inherited};
+pub use super::reload::Reloads;
+
 /// Signals a receiver to shutdown by the provided deadline.
-pub type Closer = sync::oneshot::Sender<Instant>;
+///
+/// A `Closer` may be cloned so that shutdown may be triggered from several sources.
+pub type Closer = sync::mpsc::UnboundedSender<Instant>;
 
 /// Signals that the receiver should release its resources by the provided deadline.
-pub type Closed = sync::oneshot::Receiver<Instant>;
+///
+/// The first deadline initiates shutdown. Subsequent deadlines may shorten it.
+pub type Closed = sync::mpsc::UnboundedReceiver<Instant>;
 
 /// Creates a thread-safe shutdown latch.
 pub fn closer() -> (Closer, Closed) {
-    sync::oneshot::channel()
+    sync::mpsc::unbounded()
 }
 
 /// Holds the configuration for a linkerd-tcp instance.
@@ -70,6 +90,9 @@ pub struct AppConfig {
 
     /// Configures the shared buffer used for transferring data.
     pub buffer_size_bytes: Option<usize>,
+
+    /// Configures the hand-off of listening sockets between processes during upgrades.
+    pub handoff: Option<HandoffConfig>,
 }
 
 impl ::std::str::FromStr for AppConfig {
@@ -98,6 +121,8 @@ impl AppConfig {
         let (metrics, reporter) = tacho::new();
         let metrics = metrics.prefixed("l5d");
 
+        validate_unique(&self.routers)?;
+
         // Load all router configurations.
         //
         // Separate resolver tasks are created to be executed in the admin thread's
@@ -106,7 +131,7 @@ impl AppConfig {
         let mut routers = VecDeque::with_capacity(self.routers.len());
         let mut resolvers = VecDeque::with_capacity(self.routers.len());
         for config in self.routers.drain(..) {
-            let mut r = config.into_router(buf.clone(), &metrics)?;
+            let mut r = config.into_router(&buf, &metrics)?;
             let e = r.resolver_executor.take().expect(
                 "router missing resolver executor",
             );
@@ -114,6 +139,16 @@ impl AppConfig {
             resolvers.push_back(e);
         }
 
+        let grace = {
+            let s = self.admin
+                .as_ref()
+                .and_then(|admin| admin.grace_secs)
+                .unwrap_or(DEFAULT_GRACE_SECS);
+            Duration::from_secs(s)
+        };
+
+        let (reloader, reloads) = reload::new(&metrics.clone().prefixed("config"));
+
         // Read the admin server configuration and bundle it an AdminRunner.
         let admin = {
             let addr = {
@@ -125,13 +160,6 @@ impl AppConfig {
                 );
                 net::SocketAddr::new(ip, port)
             };
-            let grace = {
-                let s = self.admin
-                    .as_ref()
-                    .and_then(|admin| admin.grace_secs)
-                    .unwrap_or(DEFAULT_GRACE_SECS);
-                Duration::from_secs(s)
-            };
             let metrics_interval = {
                 let s = self.admin
                     .as_ref()
@@ -145,16 +173,51 @@ impl AppConfig {
                 resolvers,
                 grace,
                 metrics_interval,
+                reloader,
+                listener: None,
             }
         };
 
+        let handoff = self.handoff.as_ref().map(|h| {
+            Handoff::new(PathBuf::from(&h.socket_path), grace)
+        });
+
+        let running = Running(Rc::new(RefCell::new(InnerRunning {
+            buf,
+            metrics,
+            grace,
+            config: self,
+            routers: Vec::new(),
+            servers: Vec::new(),
+        })));
+
         Ok(App {
             routers: routers,
             admin: admin,
+            handoff: handoff,
+            running: running,
+            reloads: reloads,
         })
     }
 }
 
+/// Ensures that router labels and server addresses are not reused.
+fn validate_unique(routers: &[RouterConfig]) -> Result<()> {
+    let mut labels = HashSet::new();
+    let mut addrs = HashSet::new();
+    for r in routers {
+        if !labels.insert(&r.label) {
+            return Err(Error::DuplicateRouter(r.label.clone()));
+        }
+        for s in &r.servers {
+            if !addrs.insert(s.listen_addr()) {
+                return Err(Error::DuplicateServer(s.listen_addr()));
+            }
+        }
+    }
+    Ok(())
+}
+
 
 fn localhost_addr() -> net::IpAddr {
     net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1))
@@ -166,10 +229,28 @@ pub struct App {
     pub routers: VecDeque<RouterSpawner>,
     /// Executes the admin server.
     pub admin: AdminRunner,
+    /// Hands listening sockets between processes, if configured.
+    pub handoff: Option<Handoff>,
+    /// Tracks the routers and servers running on the serving thread.
+    pub running: Running,
+    /// Receives new configurations to be applied to running routers and servers.
+    pub reloads: Reloads,
+}
+
+/// Configures the hand-off of listening sockets between processes.
+///
+/// When a process starts, it tries to obtain the listening sockets of a process already
+/// serving on `socketPath`. Once it is serving, it accepts hand-off requests from its own
+/// replacement on the same path.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HandoffConfig {
+    /// The path of the Unix control socket.
+    pub socket_path: String,
 }
 
 /// Holds the configuration for a single stream router.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct RouterConfig {
     /// A descriptive name for this router. For stats reporting.
@@ -188,43 +269,57 @@ pub struct RouterConfig {
 }
 
 impl RouterConfig {
-    /// Consumes and validates this configuration to produce a router initializer.
+    fn metrics(&self, metrics: &tacho::Scope) -> tacho::Scope {
+        metrics.clone().labeled("rt", self.label.clone())
+    }
+
+    fn mk_connector_factory(&self) -> Result<ConnectorFactory> {
+        self.client
+            .clone()
+            .unwrap_or_default()
+            .mk_connector_factory()
+            .map_err(Error::Connector)
+    }
+
+    /// Validates this configuration to produce a router initializer.
     fn into_router(
-        mut self,
-        buf: Rc<RefCell<Vec<u8>>>,
+        self,
+        buf: &Rc<RefCell<Vec<u8>>>,
         metrics: &tacho::Scope,
     ) -> Result<RouterSpawner> {
-        let metrics = metrics.clone().labeled("rt", self.label);
+        let metrics = self.metrics(metrics);
 
         // Each router has its own resolver/executor pair. The resolver is used by the
         // router. The resolver executor is used to drive execution in another thread.
         let (resolver, resolver_exec) = match self.interpreter {
-            InterpreterConfig::NamerdHttp(config) => {
-                let namerd = config.into_namerd(&metrics).map_err(Error::Interpreter)?;
+            InterpreterConfig::NamerdHttp(ref config) => {
+                let namerd = config.clone().into_namerd(&metrics).map_err(
+                    Error::Interpreter,
+                )?;
                 resolver::new(namerd)
             }
         };
 
         let balancer = {
             let metrics = metrics.clone().prefixed("balancer");
-            let client = self.client
-                .unwrap_or_default()
-                .mk_connector_factory()
-                .map_err(Error::Connector)?;
+            let client = self.mk_connector_factory()?;
             BalancerFactory::new(client, &metrics)
         };
         let router = router::new(resolver, balancer, &metrics);
 
         let mut servers = VecDeque::with_capacity(self.servers.len());
-        for config in self.servers.drain(..) {
+        for config in &self.servers {
             // The router and transfer buffer are shareable across servers.
             let server = config
                 .mk_server(router.clone(), buf.clone(), &metrics)
                 .map_err(Error::Server)?;
-            servers.push_back(server);
+            servers.push_back((config.clone(), server));
         }
 
         Ok(RouterSpawner {
+            config: self,
+            router: router,
+            metrics: metrics,
             servers: servers,
             resolver_executor: Some(resolver_exec),
         })
@@ -233,32 +328,409 @@ impl RouterConfig {
 
 /// Spawns a router by spawning all of its serving interfaces.
 pub struct RouterSpawner {
-    servers: VecDeque<server::Unbound>,
+    config: RouterConfig,
+    router: router::Router,
+    metrics: tacho::Scope,
+    servers: VecDeque<(server::ServerConfig, server::Unbound)>,
     resolver_executor: Option<resolver::Executor>,
 }
 
 impl RouterSpawner {
     /// Spawns a router by spawning all of its serving interfaces.
     ///
+    /// Servers use listening sockets from `inherited` when available. The router and
+    /// each of its servers are tracked in `running`.
+    ///
     /// Returns successfully if all servers have been bound and spawned correctly.
-    pub fn spawn(mut self, reactor: &Handle, timer: &Timer) -> Result<()> {
-        while let Some(unbound) = self.servers.pop_front() {
-            info!(
-                "routing on {} to {}",
-                unbound.listen_addr(),
-                unbound.dst_name()
-            );
-            let bound = unbound.bind(reactor, timer).expect("failed to bind server");
-            reactor.spawn(bound.map_err(|_| {}));
+    pub fn spawn(
+        mut self,
+        reactor: &Handle,
+        timer: &Timer,
+        inherited: &mut Inherited,
+        running: &Running,
+    ) -> Result<()> {
+        let mut bound = VecDeque::with_capacity(self.servers.len());
+        while let Some((config, unbound)) = self.servers.pop_front() {
+            let addr = config.listen_addr();
+            let b = match inherited.take(&addr) {
+                Some(listener) => unbound.bind_inherited(listener, reactor, timer),
+                None => unbound.bind(reactor, timer),
+            };
+            bound.push_back((config, b.map_err(|e| Error::Bind(addr, e))?));
         }
+        if let Some(watch) = self.router.watch_client_config(timer) {
+            reactor.spawn(watch);
+        }
+        let mut running = running.0.borrow_mut();
+        running.spawn_servers(&self.config.label, bound, reactor);
+        running.routers.push(RunningRouter {
+            config: self.config,
+            router: self.router,
+            metrics: self.metrics,
+        });
         Ok(())
     }
 }
 
+/// Tracks all of the routers and servers running on the serving reactor so that they
+/// may be reconfigured, handed off, or closed.
+#[derive(Clone)]
+pub struct Running(Rc<RefCell<InnerRunning>>);
+
+struct InnerRunning {
+    buf: Rc<RefCell<Vec<u8>>>,
+    metrics: tacho::Scope,
+    grace: Duration,
+    /// The most recently applied configuration, excluding its routers.
+    config: AppConfig,
+    routers: Vec<RunningRouter>,
+    servers: Vec<RunningServer>,
+}
+
+struct RunningRouter {
+    config: RouterConfig,
+    router: router::Router,
+    metrics: tacho::Scope,
+}
+
+struct RunningServer {
+    router: String,
+    config: server::ServerConfig,
+    addr: net::SocketAddr,
+    fd: RawFd,
+    closing: server::Closing,
+    done: Rc<Cell<bool>>,
+    closed: Option<unsync::oneshot::Receiver<()>>,
+}
+
+/// A router that is to be updated when a new configuration is committed.
+struct RouterUpdate {
+    spawner: RouterSpawner,
+    /// Set if the router is new or its interpreter has changed.
+    resolver_executor: Option<resolver::Executor>,
+    /// Set if an existing router's client configuration has changed.
+    connector_factory: Option<ConnectorFactory>,
+    /// Indexes of the running servers that are unchanged.
+    kept: Vec<usize>,
+    created: bool,
+}
+
+impl Running {
+    /// Stops all servers from accepting new connections, giving active streams until
+    /// `deadline` to complete.
+    pub fn close(&self, deadline: Instant) {
+        for s in self.0.borrow().servers.iter() {
+            s.closing.close(deadline);
+        }
+    }
+
+    /// Completes when all servers have closed.
+    pub fn closed(&self) -> Box<Future<Item = (), Error = ()>> {
+        let closed = self.0
+            .borrow_mut()
+            .servers
+            .iter_mut()
+            .filter_map(|s| s.closed.take())
+            .map(|c| c.then(|_| Ok::<(), ()>(())))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(closed).map(|_| {}))
+    }
+
+    /// Serves requests for copies of the listening sockets of all servers that are still
+    /// accepting connections, along with the admin server's listening socket.
+    pub fn serve_handoffs(
+        &self,
+        requests: handoff::Requests,
+        admin: (net::SocketAddr, RawFd),
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let running = self.clone();
+        let f = requests.for_each(move |rsp| {
+            let mut listeners = Vec::new();
+            let (admin_addr, admin_fd) = admin;
+            match handoff::dup_listener(admin_fd) {
+                Ok(l) => listeners.push((admin_addr, l)),
+                Err(e) => error!("{}: failed to copy admin listener: {}", admin_addr, e),
+            }
+            let running = running.0.borrow();
+            for s in running.servers.iter().filter(|s| !s.closing.is_closing()) {
+                match handoff::dup_listener(s.fd) {
+                    Ok(l) => listeners.push((s.addr, l)),
+                    Err(e) => error!("{}: failed to copy listener: {}", s.addr, e),
+                }
+            }
+            let _ = rsp.send(listeners);
+            Ok(())
+        });
+        Box::new(f)
+    }
+
+    /// Applies configurations received from `reloads` to the running routers and
+    /// servers.
+    pub fn serve_reloads(
+        &self,
+        reloads: Reloads,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let running = self.clone();
+        let reactor = reactor.clone();
+        let timer = timer.clone();
+        let f = reloads.for_each(move |req| {
+            match req {
+                reload::Request::Reload { config, rsp } => {
+                    let result = running.0.borrow_mut().reload(config, &reactor, &timer);
+                    let _ = rsp.send(result.map_err(|e| format!("{:?}", e)));
+                }
+                reload::Request::ClientConfig {
+                    router,
+                    configs,
+                    rsp,
+                } => {
+                    let result = running.0.borrow().update_client_config(&router, &configs);
+                    let _ = rsp.send(result);
+                }
+            }
+            Ok(())
+        });
+        Box::new(f)
+    }
+}
+
+impl InnerRunning {
+    /// Replaces the configurations of a router's dynamic client.
+    fn update_client_config(
+        &self,
+        router: &str,
+        configs: &[ConnectorConfig],
+    ) -> ::std::result::Result<String, String> {
+        let r = self.routers
+            .iter()
+            .find(|r| r.config.label == router)
+            .ok_or_else(|| format!("unknown router: {}", router))?;
+        let updated = r.router.update_connector_configs(configs).map_err(|e| {
+            format!("{:?}", e)
+        })?;
+        Ok(format!("routes updated={}", updated))
+    }
+
+    fn spawn_servers(
+        &mut self,
+        router: &str,
+        mut bound: VecDeque<(server::ServerConfig, server::Bound)>,
+        reactor: &Handle,
+    ) {
+        while let Some((config, bound)) = bound.pop_front() {
+            info!("{}: serving on {}", router, bound.listen_addr());
+            let (tx, rx) = unsync::oneshot::channel();
+            let done = Rc::new(Cell::new(false));
+            self.servers.push(RunningServer {
+                router: router.to_owned(),
+                addr: config.listen_addr(),
+                config: config,
+                fd: bound.as_raw_fd(),
+                closing: bound.closing(),
+                done: done.clone(),
+                closed: Some(rx),
+            });
+            reactor.spawn(bound.then(move |_| -> ::std::result::Result<(), ()> {
+                done.set(true);
+                let _ = tx.send(());
+                Ok(())
+            }));
+        }
+    }
+
+    /// Applies a new configuration.
+    ///
+    /// All new routers and servers are built and bound before any running state is
+    /// changed, so that an invalid configuration leaves the running configuration
+    /// untouched. Then:
+    ///
+    /// - servers that were removed or changed stop accepting connections and are given
+    ///   the grace period to drain;
+    /// - routers with a changed client configuration evict only the routes whose
+    ///   connector configuration has changed;
+    /// - new and changed servers begin serving.
+    ///
+    /// Unchanged routers, routes, and servers are unaffected.
+    fn reload(
+        &mut self,
+        mut config: AppConfig,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Result<reload::Reloaded> {
+        validate_unique(&config.routers)?;
+        if config.admin != self.config.admin ||
+            config.buffer_size_bytes != self.config.buffer_size_bytes ||
+            config.handoff != self.config.handoff
+        {
+            warn!("admin, buffer, and handoff configuration changes require a restart");
+        }
+
+        // Forget servers that have finished draining.
+        self.servers.retain(|s| !s.done.get());
+
+        // Prepare updates for all configured routers.
+        let mut updates = Vec::with_capacity(config.routers.len());
+        for rc in config.routers.drain(..) {
+            updates.push(self.prepare(rc)?);
+        }
+
+        // Bind all new servers. Servers that are replacing a server on the same address
+        // share its listening socket so that no connections are refused.
+        let mut bound = Vec::with_capacity(updates.len());
+        for u in &mut updates {
+            let mut b = VecDeque::with_capacity(u.spawner.servers.len());
+            while let Some((sc, unbound)) = u.spawner.servers.pop_front() {
+                let addr = sc.listen_addr();
+                let existing = self.servers.iter().find(|s| {
+                    s.addr == addr && !s.closing.is_closing()
+                });
+                let result = match existing {
+                    Some(s) => {
+                        handoff::dup_listener(s.fd).and_then(|l| {
+                            unbound.bind_inherited(l, reactor, timer)
+                        })
+                    }
+                    None => unbound.bind(reactor, timer),
+                };
+                b.push_back((sc, result.map_err(|e| Error::Bind(addr, e))?));
+            }
+            bound.push(b);
+        }
+
+        // Everything has been validated; commit the new configuration.
+        let deadline = Instant::now() + self.grace;
+        let mut kept = HashSet::new();
+        for u in &updates {
+            kept.extend(u.kept.iter().cloned());
+        }
+        let mut servers_removed = 0;
+        for (i, s) in self.servers.iter().enumerate() {
+            if !kept.contains(&i) && !s.closing.is_closing() {
+                info!("{}: closing server on {}", s.router, s.addr);
+                s.closing.close(deadline);
+                servers_removed += 1;
+            }
+        }
+
+        let mut resolvers = VecDeque::new();
+        let mut routers = Vec::with_capacity(updates.len());
+        let mut routers_added = Vec::new();
+        let mut routers_updated = Vec::new();
+        let mut servers_added = 0;
+        let mut routes_evicted = 0;
+        for (u, b) in updates.into_iter().zip(bound) {
+            let label = u.spawner.config.label.clone();
+            let client_updated = u.connector_factory.is_some();
+            if let Some(cf) = u.connector_factory {
+                routes_evicted += u.spawner.router.update_connector_factory(cf);
+                routers_updated.push(label.clone());
+            }
+            if u.created || client_updated {
+                if let Some(watch) = u.spawner.router.watch_client_config(timer) {
+                    reactor.spawn(watch);
+                }
+            }
+            if let Some(e) = u.resolver_executor {
+                resolvers.push_back(e);
+            }
+            if u.created {
+                routers_added.push(label.clone());
+            }
+            servers_added += b.len();
+            self.spawn_servers(&label, b, reactor);
+            routers.push(RunningRouter {
+                config: u.spawner.config,
+                router: u.spawner.router,
+                metrics: u.spawner.metrics,
+            });
+        }
+        let routers_removed = self.routers
+            .iter()
+            .filter(|r| !routers.iter().any(|n| n.config.label == r.config.label))
+            .map(|r| r.config.label.clone())
+            .collect::<Vec<_>>();
+        self.routers = routers;
+        self.config = config;
+
+        let summary = format!(
+            "routers added={:?} updated={:?} removed={:?}; servers added={} removed={}; \
+             routes evicted={}",
+            routers_added,
+            routers_updated,
+            routers_removed,
+            servers_added,
+            servers_removed,
+            routes_evicted
+        );
+        Ok(reload::Reloaded { resolvers, summary })
+    }
+
+    /// Determines how a router must change to satisfy `config`, building any new
+    /// components that it needs.
+    fn prepare(&self, config: RouterConfig) -> Result<RouterUpdate> {
+        let existing = self.routers.iter().find(|r| {
+            r.config.label == config.label && r.config.interpreter == config.interpreter
+        });
+        let r = match existing {
+            Some(r) => r,
+            None => {
+                // The router is new, or its interpreter changed: build a new one.
+                let mut spawner = config.into_router(&self.buf, &self.metrics)?;
+                let resolver_executor = spawner.resolver_executor.take();
+                return Ok(RouterUpdate {
+                    spawner,
+                    resolver_executor,
+                    connector_factory: None,
+                    kept: Vec::new(),
+                    created: true,
+                });
+            }
+        };
+
+        let connector_factory = if r.config.client != config.client {
+            Some(config.mk_connector_factory()?)
+        } else {
+            None
+        };
+
+        let mut kept = Vec::new();
+        let mut servers = VecDeque::new();
+        for sc in &config.servers {
+            let running = self.servers.iter().position(|s| {
+                s.router == config.label && s.config == *sc && !s.closing.is_closing()
+            });
+            match running {
+                Some(i) => kept.push(i),
+                None => {
+                    let unbound = sc.mk_server(r.router.clone(), self.buf.clone(), &r.metrics)
+                        .map_err(Error::Server)?;
+                    servers.push_back((sc.clone(), unbound));
+                }
+            }
+        }
+
+        Ok(RouterUpdate {
+            spawner: RouterSpawner {
+                config: config,
+                router: r.router.clone(),
+                metrics: r.metrics.clone(),
+                servers: servers,
+                resolver_executor: None,
+            },
+            resolver_executor: None,
+            connector_factory,
+            kept,
+            created: false,
+        })
+    }
+}
+
 /// Configures an interpreter.
 ///
 /// Currently, only the io.l5d.namerd.http interpreter is supported.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, tag = "kind")]
 pub enum InterpreterConfig {
     /// Polls namerd for updates.
@@ -267,7 +739,7 @@ pub enum InterpreterConfig {
 }
 
 /// Configures the admin server.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct AdminConfig {
     /// The port on which the admin server listens.
@@ -291,20 +763,51 @@ pub struct AdminRunner {
     resolvers: VecDeque<resolver::Executor>,
     grace: Duration,
     metrics_interval: Duration,
+    reloader: reload::Reloader,
+    /// The admin server's listening socket, once bound.
+    listener: Option<net::TcpListener>,
 }
 
 impl AdminRunner {
+    /// Configures the file from which configuration is reloaded on SIGHUP or via the
+    /// admin API.
+    pub fn reload_config_from<P: Into<PathBuf>>(&mut self, path: P) {
+        self.reloader.set_path(path.into());
+    }
+
+    /// Binds the admin server's listening socket, using a socket handed off by a
+    /// previous process from `inherited` if there is one.
+    ///
+    /// The socket is bound before the admin server runs so that it may be handed off in
+    /// turn. Returns its address and descriptor.
+    pub fn bind(&mut self, inherited: &mut Inherited) -> Result<(net::SocketAddr, RawFd)> {
+        let addr = self.addr;
+        let listener = match inherited.take(&addr) {
+            Some(l) => {
+                debug!("admin inherited listener on {}", addr);
+                l
+            }
+            None => net::TcpListener::bind(&addr).map_err(|e| Error::Bind(addr, e))?,
+        };
+        let fd = listener.as_raw_fd();
+        self.listener = Some(listener);
+        Ok((addr, fd))
+    }
+
     /// Runs the admin server on the provided reactor.
     ///
-    /// When the _shutdown_ endpoint is triggered, a shutdown deadline is sent on
-    /// `closer`.
+    /// When the _shutdown_ endpoint is triggered or the process receives SIGTERM or
+    /// SIGINT, a shutdown deadline is sent on `closer`. When the _config/reload_ endpoint
+    /// is triggered or the process receives SIGHUP, the configuration is reloaded.
     pub fn run(self, closer: Closer, reactor: &mut Core, timer: &Timer) -> Result<()> {
         let AdminRunner {
             addr,
             grace,
             metrics_interval,
+            reloader,
             mut reporter,
             mut resolvers,
+            listener,
         } = self;
 
         let handle = reactor.handle();
@@ -312,6 +815,17 @@ impl AdminRunner {
             handle.spawn(resolver.execute(&handle, timer));
         }
 
+        handle.spawn(signals::close_on_termination(
+            closer.clone(),
+            grace,
+            &handle,
+        ));
+        handle.spawn(signals::reload_on_hangup(
+            reloader.clone(),
+            &handle,
+            timer,
+        ));
+
         let prom_export = Rc::new(RefCell::new(String::with_capacity(8 * 1024)));
         let reporting = {
             let prom_export = prom_export.clone();
@@ -330,14 +844,22 @@ impl AdminRunner {
         handle.spawn(reporting);
 
         let serving = {
-            let listener = {
-                info!("admin listening on http://{}.", addr);
-                TcpListener::bind(&addr, &handle).expect("unable to listen")
+            let listener = match listener {
+                Some(l) => TcpListener::from_listener(l, &addr, &handle),
+                None => TcpListener::bind(&addr, &handle),
             };
+            let listener = listener.map_err(|e| Error::Bind(addr, e))?;
+            info!("admin listening on http://{}.", addr);
 
             let serve_handle = handle.clone();
-            let server =
-                admin::Admin::new(prom_export, closer, grace, handle.clone(), timer.clone());
+            let server = admin::Admin::new(
+                prom_export,
+                closer,
+                grace,
+                reloader,
+                handle.clone(),
+                timer.clone(),
+            );
             let http = Http::<hyper::Chunk>::new();
             listener.incoming()
                 .for_each(move |(tcp, _)| {
@@ -0,0 +1,204 @@
+//! Limits additional connection attempts relative to the connections requested.
+
+use super::super::connector::RetryBudget;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Tracks the connections requested and retries made over a sliding window.
+///
+/// As with Finagle's retry budget, each requested connection earns a fraction of a
+/// retry, and a minimum number of retries per second is always allowed, so that a single
+/// failure is retried even when few connections are requested. Earnings expire after the
+/// budget's TTL, so that a burst of requests cannot fund a later burst of retries.
+pub struct Budget {
+    config: RetryBudget,
+    epoch: Instant,
+    /// Deposits and withdrawals in each second of the TTL, oldest first.
+    slots: VecDeque<Slot>,
+}
+
+struct Slot {
+    second: u64,
+    deposits: usize,
+    withdrawals: usize,
+}
+
+/// A retry spent from a budget, which may be refunded if it is not attempted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Withdrawal {
+    epoch: Instant,
+    second: u64,
+}
+
+impl Budget {
+    pub fn new(config: RetryBudget) -> Budget {
+        Budget {
+            config,
+            epoch: Instant::now(),
+            slots: VecDeque::new(),
+        }
+    }
+
+    /// Records a requested connection.
+    pub fn deposit(&mut self) {
+        let now = self.now();
+        self.deposit_at(now);
+    }
+
+    /// Attempts to spend a retry, returning `None` if none remain.
+    pub fn withdraw(&mut self) -> Option<Withdrawal> {
+        let now = self.now();
+        self.withdraw_at(now)
+    }
+
+    /// Returns a retry that was not attempted.
+    ///
+    /// If the second in which the retry was withdrawn has expired, the withdrawal no
+    /// longer counts against the budget and nothing is refunded.
+    pub fn refund(&mut self, withdrawal: Withdrawal) {
+        let now = self.now();
+        self.refund_at(withdrawal, now);
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_secs()
+    }
+
+    fn deposit_at(&mut self, second: u64) {
+        self.slot(second).deposits += 1;
+    }
+
+    fn withdraw_at(&mut self, second: u64) -> Option<Withdrawal> {
+        self.slot(second);
+        let (deposits, withdrawals) = self.slots.iter().fold((0, 0), |(d, w), s| {
+            (d + s.deposits, w + s.withdrawals)
+        });
+        let ttl = self.config.ttl.as_secs() as f64;
+        let reserve = f64::from(self.config.min_retries_per_sec) * ttl;
+        let earned = self.config.percent_can_retry * deposits as f64;
+        if reserve + earned - (withdrawals as f64) < 1.0 {
+            return None;
+        }
+        self.slot(second).withdrawals += 1;
+        Some(Withdrawal {
+            epoch: self.epoch,
+            second,
+        })
+    }
+
+    fn refund_at(&mut self, withdrawal: Withdrawal, second: u64) {
+        // Withdrawals from a replaced budget don't count against this one.
+        if withdrawal.epoch != self.epoch {
+            return;
+        }
+        self.expire(second);
+        let slot = self.slots.iter_mut().find(|s| s.second == withdrawal.second);
+        if let Some(slot) = slot {
+            slot.withdrawals = slot.withdrawals.saturating_sub(1);
+        }
+    }
+
+    /// Expires slots older than the TTL.
+    fn expire(&mut self, second: u64) {
+        let ttl = self.config.ttl.as_secs();
+        while self.slots.front().map(|s| s.second + ttl <= second).unwrap_or(false) {
+            self.slots.pop_front();
+        }
+    }
+
+    /// Expires slots older than the TTL and returns the slot for the given second.
+    fn slot(&mut self, second: u64) -> &mut Slot {
+        self.expire(second);
+        if self.slots.back().map(|s| s.second != second).unwrap_or(true) {
+            self.slots.push_back(Slot {
+                second,
+                deposits: 0,
+                withdrawals: 0,
+            });
+        }
+        self.slots.back_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, Withdrawal};
+    use super::super::super::connector::RetryBudget;
+    use std::time::Duration;
+
+    /// Allows one retry per second over a 10s TTL, plus one retry per 10 requests.
+    fn budget() -> Budget {
+        Budget::new(RetryBudget {
+            ttl: Duration::from_secs(10),
+            min_retries_per_sec: 1,
+            percent_can_retry: 0.1,
+        })
+    }
+
+    #[test]
+    fn withdraw_reserve() {
+        let mut b = budget();
+        for _ in 0..10 {
+            assert!(b.withdraw_at(0).is_some());
+        }
+        assert!(b.withdraw_at(0).is_none());
+    }
+
+    #[test]
+    fn deposits_earn_retries() {
+        let mut b = budget();
+        for _ in 0..20 {
+            b.deposit_at(0);
+        }
+        for _ in 0..12 {
+            assert!(b.withdraw_at(0).is_some());
+        }
+        assert!(b.withdraw_at(0).is_none());
+    }
+
+    #[test]
+    fn refund_restores_retry() {
+        let mut b = budget();
+        let mut withdrawals = Vec::new();
+        while let Some(w) = b.withdraw_at(0) {
+            withdrawals.push(w);
+        }
+        b.refund_at(withdrawals.pop().unwrap(), 1);
+        assert!(b.withdraw_at(1).is_some());
+        assert!(b.withdraw_at(1).is_none());
+    }
+
+    #[test]
+    fn withdrawals_expire() {
+        let mut b = budget();
+        while b.withdraw_at(0).is_some() {}
+        assert!(b.withdraw_at(9).is_none());
+        assert!(b.withdraw_at(10).is_some());
+    }
+
+    #[test]
+    fn expired_withdrawal_is_not_refunded() {
+        let mut b = budget();
+        let expired = b.withdraw_at(0).unwrap();
+        for _ in 0..9 {
+            assert!(b.withdraw_at(5).is_some());
+        }
+        assert!(b.withdraw_at(10).is_some());
+        assert!(b.withdraw_at(10).is_none());
+        // The expired withdrawal must not cancel a live one.
+        b.refund_at(expired, 10);
+        assert!(b.withdraw_at(10).is_none());
+    }
+
+    #[test]
+    fn refund_from_replaced_budget_is_ignored() {
+        let mut b = budget();
+        while b.withdraw_at(0).is_some() {}
+        let w = Withdrawal {
+            epoch: b.epoch + Duration::from_secs(1),
+            second: 0,
+        };
+        b.refund_at(w, 0);
+        assert!(b.withdraw_at(0).is_none());
+    }
+}
@@ -1,23 +1,26 @@
-use super::{Endpoints, EndpointMap, Waiter, WeightedAddr};
-use super::endpoint::{self, Endpoint};
+use super::{Endpoints, SrcMeta, Waiter, WeightedAddr};
+use super::budget::{Budget, Withdrawal};
+use super::endpoint;
+use super::strategy::{self, Strategy};
 use super::super::Path;
 use super::super::connection::Connection;
-use super::super::connector::Connector;
+use super::super::connector::{ConnectError, Connector, ConnectorUpdates, FailFast, Hedge,
+                              SlowStart, TlsMetrics};
 use super::super::resolver::Resolve;
 use futures::{Future, Stream, Poll, Async};
-use rand::{self, Rng};
-use std::collections::VecDeque;
-use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::{io, mem, net};
 use std::time::{Duration, Instant};
 use tacho;
 use tokio_core::reactor::Handle;
-use tokio_timer::Timer;
+use tokio_timer::{Interval, Sleep, Timer};
 
 pub fn new<S>(
     reactor: Handle,
     timer: Timer,
     dst_name: Path,
     connector: Connector,
+    connector_updates: ConnectorUpdates,
     resolve: Resolve,
     waiters_rx: S,
     endpoints: Endpoints,
@@ -26,6 +29,10 @@ pub fn new<S>(
 where
     S: Stream<Item = Waiter>,
 {
+    let health_checks = connector.health_check().map(|hc| timer.interval(hc.interval));
+    if let Some(watch) = connector.watch_identity(&timer) {
+        reactor.spawn(watch);
+    }
     Dispatcher {
         reactor,
         timer,
@@ -33,14 +40,24 @@ where
         endpoints,
         resolve,
         waiters_rx,
+        waiters_closed: false,
         max_waiters: connector.max_waiters(),
         min_connections: connector.min_connections(),
-        fail_limit: connector.failure_limit(),
-        fail_penalty: connector.failure_penalty(),
+        fail_fast: connector.fail_fast().clone(),
+        slow_start: connector.slow_start(),
+        health_checks,
+        strategy: strategy::new(connector.load_balancer()),
+        latency_decay: connector.load_balancer().latency_decay(),
+        retry_budget: Budget::new(connector.retry_budget()),
+        retries: VecDeque::default(),
+        hedging: connector.hedge().map(Hedging::new),
         connector,
+        connector_updates,
         connecting: VecDeque::default(),
         connected: VecDeque::default(),
         waiters: VecDeque::default(),
+        dedicated_waiters: VecDeque::default(),
+        dedicated: VecDeque::default(),
         metrics: Metrics::new(metrics),
     }
 }
@@ -56,26 +73,46 @@ pub struct Dispatcher<W> {
     /// Handles destination-specific connection policy.
     connector: Connector,
 
+    /// Provides connectors that replace `connector` when its configuration changes.
+    connector_updates: ConnectorUpdates,
+
     /// Provides new service discovery resolutions as a Stream.
     resolve: Resolve,
 
     /// Holds the state of all available/failed/retired endpoints.
     endpoints: Endpoints,
 
-    /// Limits the number of consecutive failures allowed before an endpoint is marked as
-    /// failed.
-    fail_limit: usize,
+    /// Controls when endpoints are ejected, and for how long.
+    fail_fast: FailFast,
+
+    /// Controls how newly available endpoints are warmed up.
+    slow_start: Option<SlowStart>,
 
-    /// Controls how long an endpoint will be marked as failed before being considered for
-    /// new connections.s
-    fail_penalty: Duration,
+    /// Fires when endpoints' health should be checked, if health checks are configured.
+    health_checks: Option<Interval>,
 
     /// Controls the minimum number of connecting/connected connections to be maintained
     /// at all times.
     min_connections: usize,
 
+    /// Selects the endpoint to which each new connection is established.
+    strategy: Box<Strategy>,
+
+    /// Controls how quickly endpoints' connect latency estimates forget older
+    /// connections.
+    latency_decay: Duration,
+
+    /// Limits retries of failed connection attempts.
+    retry_budget: Budget,
+
+    /// Failed connection attempts to be retried by `init_connecting`.
+    retries: VecDeque<Retry>,
+
+    /// Controls when slow connection attempts are hedged, if they may be.
+    hedging: Option<Hedging>,
+
     /// A queue of pending connections.
-    connecting: VecDeque<tacho::Timed<endpoint::Connecting>>,
+    connecting: VecDeque<Attempt>,
 
     /// A queue of ready connections to be dispatched ot waiters.
     connected: VecDeque<Connection<endpoint::Ctx>>,
@@ -83,32 +120,136 @@ pub struct Dispatcher<W> {
     /// Provides new connection requests as a Stream..
     waiters_rx: W,
 
+    /// Indicates that `waiters_rx` has completed, i.e. because the balancer has been
+    /// dropped by its router.
+    waiters_closed: bool,
+
     /// A queue of waiters that have not yet received a connection.
     waiters: VecDeque<Waiter>,
 
     /// Limits the size of `waiters`.
     max_waiters: usize,
 
+    /// Waiters that must be satisfied by a connection established specifically for them,
+    /// i.e. because their SNI is forwarded to the endpoint, or because the endpoint is
+    /// chosen by hashing their source. Waiters whose connection failed are requeued with
+    /// the attempt to be retried.
+    dedicated_waiters: VecDeque<(Waiter, Option<Retry>)>,
+
+    /// Connections being established for specific waiters.
+    dedicated: VecDeque<(Waiter, Attempt)>,
+
     metrics: Metrics,
 }
 
+/// A pending connection to an endpoint.
+struct Attempt {
+    addr: net::SocketAddr,
+    /// The number of attempts, including this one, made to establish the connection.
+    count: usize,
+    connecting: tacho::Timed<endpoint::Connecting>,
+    /// Fires when the attempt should be hedged, if it may be.
+    hedge_at: Option<Sleep>,
+    /// A connection to another endpoint, racing this one because it was slow.
+    hedge: Option<(net::SocketAddr, tacho::Timed<endpoint::Connecting>)>,
+}
+
+impl Attempt {
+    /// Describes a retry of this attempt, which should be made on another endpoint.
+    fn retry(&self, withdrawal: Withdrawal) -> Retry {
+        Retry {
+            failed: self.addr,
+            attempts: self.count,
+            withdrawal,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Retry {
+    failed: net::SocketAddr,
+    attempts: usize,
+    /// Refunded to the retry budget if the retry is abandoned.
+    withdrawal: Withdrawal,
+}
+
+struct Hedging {
+    delay: Duration,
+    budget: Budget,
+}
+
+impl Hedging {
+    fn new(hedge: Hedge) -> Hedging {
+        Hedging {
+            delay: hedge.delay,
+            budget: Budget::new(hedge.budget),
+        }
+    }
+}
+
 impl<W> Dispatcher<W>
 where
     W: Stream<Item = Waiter>,
 {
+    /// Applies the most recent connector configuration to new connections.
+    ///
+    /// Established and pending connections are not affected.
+    fn update_connector(&mut self) {
+        while let Ok(Async::Ready(Some(c))) = self.connector_updates.poll() {
+            debug!("{}: updating connector", self.dst_name);
+            if let Some(watch) = c.watch_identity(&self.timer) {
+                self.reactor.spawn(watch);
+            }
+            self.max_waiters = c.max_waiters();
+            self.min_connections = c.min_connections();
+            self.fail_fast = c.fail_fast().clone();
+            self.slow_start = c.slow_start();
+            if c.health_check() != self.connector.health_check() {
+                self.health_checks = c.health_check().map(|hc| self.timer.interval(hc.interval));
+                if self.health_checks.is_none() {
+                    self.endpoints.reset_health();
+                }
+            }
+            if c.load_balancer() != self.connector.load_balancer() {
+                self.strategy = strategy::new(c.load_balancer());
+                self.latency_decay = c.load_balancer().latency_decay();
+            }
+            if c.retry_budget() != self.connector.retry_budget() {
+                self.retry_budget = Budget::new(c.retry_budget());
+            }
+            if c.hedge() != self.connector.hedge() {
+                self.hedging = c.hedge().map(Hedging::new);
+            }
+            self.connector = c;
+        }
+    }
+
     /// Receives and attempts to dispatch new waiters.
     ///
     /// If there are no available connections to be dispatched, up to `max_waiters` are
     /// buffered.
     fn recv_waiters(&mut self) {
-        while self.waiters.len() < self.max_waiters {
+        while self.waiters.len() + self.dedicated_waiters.len() < self.max_waiters {
             match self.waiters_rx.poll() {
-                Ok(Async::Ready(None)) |
+                Ok(Async::Ready(None)) => {
+                    self.waiters_closed = true;
+                    return;
+                }
                 Ok(Async::NotReady) => return,
                 Err(_) => {
                     error!("{}: error from waiters channel", self.dst_name);
                 }
                 Ok(Async::Ready(Some(w))) => {
+                    self.retry_budget.deposit();
+                    if let Some(ref mut h) = self.hedging {
+                        h.budget.deposit();
+                    }
+                    if self.strategy.has_affinity() ||
+                        (self.connector.forwards_sni() && w.src().sni.is_some())
+                    {
+                        self.dedicated_waiters.push_back((w, None));
+                        continue;
+                    }
                     match self.connected.pop_front() {
                         None => self.waiters.push_back(w),
                         Some(conn) => {
@@ -122,29 +263,86 @@ where
         }
     }
 
-    fn poll_connecting(&mut self) {
+    /// Polls pending connections, adding established connections to `connected`.
+    ///
+    /// Returns true if failed attempts are to be retried by `init_connecting`.
+    fn poll_connecting(&mut self) -> bool {
         debug!("polling {} pending connections", self.connecting.len());
+        let mut retry = false;
         for _ in 0..self.connecting.len() {
-            let mut connecting = self.connecting.pop_front().unwrap();
-            match connecting.poll() {
+            let mut attempt = self.connecting.pop_front().unwrap();
+            match self.poll_attempt(&mut attempt, None) {
                 Err(e) => {
-                    debug!("connection failed: {}", e);
+                    debug!("connection to {} failed: {}", attempt.addr, e);
                     self.metrics.pending.decr(1);
                     self.metrics.failure(&e);
+
+                    // Attempts beyond those needed to satisfy waiters, i.e. to maintain
+                    // `min_connections`, are replaced without spending the budget.
+                    let pending = self.connecting.len() + self.connected.len();
+                    if self.waiters.len() <= pending {
+                        continue;
+                    }
+                    if let Some(withdrawal) = self.retry_budget.withdraw() {
+                        self.retries.push_back(attempt.retry(withdrawal));
+                        retry = true;
+                    } else {
+                        debug!("retry budget exhausted");
+                        self.metrics.retries_exhausted.incr(1);
+                        self.waiters.pop_front().unwrap().fail(e);
+                    }
                 }
                 Ok(Async::NotReady) => {
                     trace!("connection pending");
-                    self.connecting.push_back(connecting);
+                    self.connecting.push_back(attempt);
                 }
                 Ok(Async::Ready(connected)) => {
                     debug!("connected");
                     self.metrics.connects.incr(1);
                     self.metrics.pending.decr(1);
                     self.metrics.open.incr(1);
+                    self.metrics.attempts_per_connection.add(attempt.count as u64);
                     self.connected.push_back(connected)
                 }
             }
         }
+        retry
+    }
+
+    /// Probes the health of each available and failed endpoint when health checks are
+    /// due. Probe results are applied when endpoints are next updated.
+    fn check_health(&mut self) {
+        let due = match self.health_checks {
+            None => return,
+            Some(ref mut interval) => {
+                let mut due = false;
+                while let Ok(Async::Ready(Some(_))) = interval.poll() {
+                    due = true;
+                }
+                due
+            }
+        };
+        let hc = match self.connector.health_check() {
+            Some(hc) if due => hc,
+            _ => return,
+        };
+        let available = self.endpoints.available().values();
+        let failed = self.endpoints.failed().values().map(|&(_, ref ep)| ep);
+        for ep in available.chain(failed) {
+            if ep.is_probing() {
+                continue;
+            }
+            let probe = self.connector.probe(
+                &ep.peer_addr(),
+                ep.meta(),
+                &self.reactor,
+                &self.timer,
+            );
+            if let Some(probe) = probe {
+                self.metrics.health_checks.incr(1);
+                self.reactor.spawn(ep.check_health(probe, hc));
+            }
+        }
     }
 
     fn update_endpoints(&mut self) {
@@ -158,11 +356,9 @@ where
             );
         }
 
-        self.endpoints.update_failed(
-            self.fail_limit,
-            self.fail_penalty,
-        );
-
+        let ejected = self.endpoints.update_failed(&self.fail_fast);
+        self.metrics.ejections.incr(ejected);
+        self.endpoints.update_weights(self.slow_start);
     }
 
     fn poll_resolve(&mut self) -> Option<Vec<WeightedAddr>> {
@@ -191,13 +387,33 @@ where
         }
     }
 
+    /// Initiates connections for waiters and to maintain `min_connections`, retrying
+    /// failed attempts first.
     fn init_connecting(&mut self) {
-        let available = self.endpoints.available();
-        if available.is_empty() {
+        let mut retries = mem::replace(&mut self.retries, VecDeque::new());
+        self.connect_needed(&mut retries);
+
+        // Retries that can't be initiated now are abandoned and refunded to the budget;
+        // their waiters are satisfied by later connections, or time out.
+        for retry in retries {
+            self.retry_budget.refund(retry.withdrawal);
+        }
+    }
+
+    /// Initiates the connections needed by `init_connecting`, taking failed attempts
+    /// from `retries` as they are retried.
+    fn connect_needed(&mut self, retries: &mut VecDeque<Retry>) {
+        if self.endpoints.available().is_empty() {
             trace!("no available endpoints");
             return;
         }
 
+        // Connections can't be established in advance if the endpoint depends on the
+        // inbound connection.
+        if self.strategy.has_affinity() {
+            return;
+        }
+
         let needed = {
             let needed = self.min_connections + self.waiters.len();
             let pending = self.connecting.len() + self.connected.len();
@@ -209,48 +425,227 @@ where
         };
         debug!("initiating {} connections", needed);
 
-        let mut rng = rand::thread_rng();
         for _ in 0..needed {
-            match select_endpoint(&mut rng, available) {
+            let retry = retries.pop_front();
+            match self.connect_endpoint(None, retry) {
                 None => {
-                    trace!("no endpoints ready");
-                    self.metrics.unavailable.incr(1);
+                    retries.extend(retry);
                     return;
                 }
-                Some(ep) => {
-                    self.metrics.attempts.incr(1);
-                    let mut conn = {
-                        let sock = self.connector.connect(
-                            &ep.peer_addr(),
-                            &self.reactor,
-                            &self.timer,
-                        );
-                        let c = ep.connect(sock, &self.metrics.connection_duration);
-                        self.metrics.connect_latency.time(c)
-                    };
-                    match conn.poll() {
-                        Err(e) => {
-                            debug!("connection failed: {}", e);
-                            self.metrics.failure(&e);
-                        }
-                        Ok(Async::NotReady) => {
-                            trace!("connection pending");
-                            self.metrics.pending.incr(1);
-                            self.connecting.push_back(conn);
-                        }
-                        Ok(Async::Ready(conn)) => {
-                            debug!("connected");
-                            self.metrics.connects.incr(1);
-                            self.metrics.pending.decr(1);
-                            self.metrics.open.incr(1);
-                            self.connected.push_back(conn);
-                        }
+                Some(attempt) => {
+                    if retry.is_some() {
+                        self.metrics.retries.incr(1);
+                    }
+                    self.metrics.pending.incr(1);
+                    self.connecting.push_back(attempt);
+                }
+            }
+        }
+    }
+
+    /// Initiates a connection for each waiter that requires its own connection.
+    fn init_dedicated(&mut self) {
+        if self.endpoints.available().is_empty() {
+            return;
+        }
+
+        while let Some((waiter, retry)) = self.dedicated_waiters.pop_front() {
+            let attempt = self.connect_endpoint(Some(waiter.src()), retry);
+            match attempt {
+                None => {
+                    self.dedicated_waiters.push_front((waiter, retry));
+                    return;
+                }
+                Some(attempt) => {
+                    if retry.is_some() {
+                        self.metrics.retries.incr(1);
+                    }
+                    self.metrics.pending.incr(1);
+                    self.dedicated.push_back((waiter, attempt));
+                }
+            }
+        }
+    }
+
+    /// Polls connections being established for specific waiters, dispatching them as
+    /// they are established.
+    ///
+    /// Returns true if failed attempts are to be retried by `init_dedicated`.
+    fn poll_dedicated(&mut self) -> bool {
+        let mut retry = false;
+        for _ in 0..self.dedicated.len() {
+            let (waiter, mut attempt) = self.dedicated.pop_front().unwrap();
+            match self.poll_attempt(&mut attempt, Some(waiter.src())) {
+                Err(e) => {
+                    debug!("connection to {} failed: {}", attempt.addr, e);
+                    self.metrics.pending.decr(1);
+                    self.metrics.failure(&e);
+                    if let Some(withdrawal) = self.retry_budget.withdraw() {
+                        let retry = attempt.retry(withdrawal);
+                        self.dedicated_waiters.push_back((waiter, Some(retry)));
+                        retry = true;
+                    } else {
+                        debug!("retry budget exhausted");
+                        self.metrics.retries_exhausted.incr(1);
+                        waiter.fail(e);
                     }
                 }
+                Ok(Async::NotReady) => {
+                    self.dedicated.push_back((waiter, attempt));
+                }
+                Ok(Async::Ready(conn)) => {
+                    debug!("connected");
+                    self.metrics.connects.incr(1);
+                    self.metrics.pending.decr(1);
+                    self.metrics.open.incr(1);
+                    self.metrics.attempts_per_connection.add(attempt.count as u64);
+                    // If the waiter has gone away, the connection is dropped, since it
+                    // was established for the waiter's server name.
+                    let _ = waiter.send(conn);
+                }
+            }
+        }
+        retry
+    }
+
+    /// Polls a pending connection, hedging it if it has been pending for too long.
+    ///
+    /// Whichever of the connection and its hedge is established first is used, and the
+    /// other is canceled. If either fails, the other is awaited.
+    fn poll_attempt(
+        &mut self,
+        attempt: &mut Attempt,
+        src: Option<&SrcMeta>,
+    ) -> Poll<endpoint::Connection, io::Error> {
+        match attempt.connecting.poll() {
+            Ok(Async::NotReady) => {}
+            Err(e) => {
+                if let Some((addr, hedge)) = attempt.hedge.take() {
+                    debug!("connection to {} failed: {}", attempt.addr, e);
+                    self.metrics.failure(&e);
+                    attempt.addr = addr;
+                    attempt.connecting = hedge;
+                    return self.poll_attempt(attempt, src);
+                }
+                return Err(e);
+            }
+            // The hedge, if any, is canceled when the attempt is dropped.
+            ready => return ready,
+        }
+
+        self.hedge(attempt, src);
+        if let Some((addr, mut hedge)) = attempt.hedge.take() {
+            match hedge.poll() {
+                Ok(Async::NotReady) => attempt.hedge = Some((addr, hedge)),
+                Err(e) => {
+                    debug!("hedged connection to {} failed: {}", addr, e);
+                    self.metrics.failure(&e);
+                }
+                Ok(Async::Ready(conn)) => {
+                    debug!("hedged connection to {} won", addr);
+                    self.metrics.hedge_wins.incr(1);
+                    attempt.addr = addr;
+                    attempt.connecting = hedge;
+                    return Ok(Async::Ready(conn));
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    /// Starts a connection to another endpoint to race a slow attempt, once the attempt
+    /// has been pending for the hedge delay, if the hedge budget allows.
+    fn hedge(&mut self, attempt: &mut Attempt, src: Option<&SrcMeta>) {
+        match attempt.hedge_at.as_mut().map(|t| t.poll()) {
+            None |
+            Some(Ok(Async::NotReady)) => return,
+            Some(_) => attempt.hedge_at = None,
+        }
+
+        let available = self.endpoints.available();
+        let ep = match self.strategy.select_retry(available, src, &attempt.addr) {
+            Some(ep) if ep.peer_addr() != attempt.addr => ep,
+            _ => {
+                trace!("no other endpoint to hedge {}", attempt.addr);
+                return;
+            }
+        };
+        let allowed = match self.hedging {
+            Some(ref mut h) => h.budget.withdraw().is_some(),
+            None => false,
+        };
+        if !allowed {
+            debug!("hedge budget exhausted");
+            self.metrics.hedges_exhausted.incr(1);
+            return;
+        }
+
+        debug!("hedging connection to {} with {}", attempt.addr, ep.peer_addr());
+        self.metrics.hedges.incr(1);
+        attempt.hedge = Some((ep.peer_addr(), self.connect(ep, src)));
+    }
+
+    /// Selects an endpoint and initiates a connection to it.
+    ///
+    /// If the connection is established for a specific inbound connection, `src` may
+    /// determine the endpoint, and its SNI may be sent to the endpoint in place of the
+    /// destination's TLS name. If the connection retries a failed attempt, another
+    /// endpoint is preferred.
+    fn connect_endpoint(&self, src: Option<&SrcMeta>, retry: Option<Retry>) -> Option<Attempt> {
+        let available = self.endpoints.available();
+        let selected = match retry {
+            None => self.strategy.select(available, src),
+            Some(r) => self.strategy.select_retry(available, src, &r.failed),
+        };
+        match selected {
+            None => {
+                trace!("no endpoints ready");
+                self.metrics.unavailable.incr(1);
+                None
+            }
+            Some(ep) => {
+                // Connections for inbound connections with affinity to an endpoint are
+                // not hedged on other endpoints.
+                let hedge_at = match self.hedging {
+                    Some(ref h) if !self.strategy.has_affinity() => {
+                        Some(self.timer.sleep(h.delay))
+                    }
+                    _ => None,
+                };
+                Some(Attempt {
+                    addr: ep.peer_addr(),
+                    count: retry.map(|r| r.attempts + 1).unwrap_or(1),
+                    connecting: self.connect(ep, src),
+                    hedge_at,
+                    hedge: None,
+                })
             }
         }
     }
 
+    /// Initiates a connection to an endpoint.
+    ///
+    /// If the connection is established for a specific inbound connection, its SNI may
+    /// be sent to the endpoint in place of the destination's TLS name.
+    fn connect(
+        &self,
+        ep: &endpoint::Endpoint,
+        src: Option<&SrcMeta>,
+    ) -> tacho::Timed<endpoint::Connecting> {
+        self.metrics.attempts.incr(1);
+        let sni = src.and_then(|s| s.sni.as_ref()).map(|s| s.as_str());
+        let sock = self.connector.connect(
+            &ep.peer_addr(),
+            ep.meta(),
+            sni,
+            &self.metrics.tls,
+            &self.reactor,
+            &self.timer,
+        );
+        let c = ep.connect(sock, self.latency_decay, &self.metrics.connection_duration);
+        self.metrics.connect_latency.time(c)
+    }
+
     fn dispatch_connected_to_waiters(&mut self) {
         debug!(
             "dispatching {} connections to {} waiters",
@@ -280,7 +675,8 @@ where
         }
     }
 
-    fn record(&self, t0: Instant) {
+    fn record(&mut self, t0: Instant) {
+        self.record_endpoints();
         {
             let mut open = 0;
             let mut pending = 0;
@@ -314,9 +710,38 @@ where
             self.metrics.open.set(open);
             self.metrics.pending.set(pending);
         }
-        self.metrics.waiters.set(self.waiters.len());
+        self.metrics.waiters.set(
+            self.waiters.len() + self.dedicated_waiters.len() + self.dedicated.len(),
+        );
         self.metrics.poll_time.record_since(t0);
     }
+
+    /// Reports the weight used to balance load over each endpoint, and its health.
+    /// Failed endpoints are reported with a weight of zero.
+    fn record_endpoints(&mut self) {
+        let scope = &self.metrics.endpoint;
+        let gauges = &mut self.metrics.endpoints;
+        let available = self.endpoints.available();
+        let failed = self.endpoints.failed();
+        gauges.retain(|addr, _| available.contains_key(addr) || failed.contains_key(addr));
+        let mut record = |addr: &net::SocketAddr, ep: &endpoint::Endpoint, weight: f64| {
+            let g = gauges.entry(*addr).or_insert_with(|| {
+                let scope = scope.clone().labeled("addr", addr.to_string());
+                EndpointGauges {
+                    weight: scope.gauge("weight_ppm"),
+                    healthy: scope.gauge("healthy"),
+                }
+            });
+            g.weight.set((weight * 1_000_000.0) as usize);
+            g.healthy.set(if ep.is_healthy() { 1 } else { 0 });
+        };
+        for (addr, ep) in available {
+            record(addr, ep, ep.weight());
+        }
+        for (addr, &(_, ref ep)) in failed {
+            record(addr, ep, 0.0);
+        }
+    }
 }
 
 /// Buffers up to `max_waiters` concurrent connection requests, along with corresponding
@@ -331,9 +756,12 @@ where
     fn poll(&mut self) -> Poll<(), io::Error> {
         let t0 = Instant::now();
 
+        self.update_connector();
+
         // Poll all pending connections. Newly established connections are added to the
-        // `connected` queue, to be dispatched.
+        // `connected` queue, to be dispatched. Failed attempts are retried below.
         self.poll_connecting();
+        self.poll_dedicated();
 
         // Now that we may have new established connnections, dispatch them to waiters.
         self.dispatch_connected_to_waiters();
@@ -349,8 +777,20 @@ where
 
         // Update our lists of endpoints from service discovery before initiating new
         // connections for pending waiters.
+        self.check_health();
         self.update_endpoints();
+        //
+        // New connections are polled immediately so that this task is notified when they
+        // complete. Connections that fail immediately are retried until they're pending
+        // or the retry budget is exhausted.
         self.init_connecting();
+        while self.poll_connecting() {
+            self.init_connecting();
+        }
+        self.init_dedicated();
+        while self.poll_dedicated() {
+            self.init_dedicated();
+        }
 
         // Dispatch any remaining available connections to any remaining waiters. This is
         // necessary because `init_connecting()` can technically satisfy connections
@@ -365,73 +805,18 @@ where
         // Update gauges & record the time it took to poll.
         self.record(t0);
 
-        // This Future never completes.
-        // TODO graceful shutdown.
-        Ok(Async::NotReady)
-    }
-}
-
-/// Selects an endpoint using the power of two choices.
-///
-/// Two endpoints are chosen randomly and return the lesser-loaded endpoint.
-/// If no endpoints are available, `None` is retruned.
-fn select_endpoint<'r, 'e, R: Rng>(
-    rng: &'r mut R,
-    available: &'e EndpointMap,
-) -> Option<&'e Endpoint> {
-    match available.len() {
-        0 => None,
-        1 => {
-            // One endpoint, use it.
-            available.get_index(0).map(|(_, ep)| ep)
-        }
-        sz => {
-            // Pick 2 candidate indices.
-            let (i0, i1) = if sz == 2 {
-                if rng.gen::<bool>() { (0, 1) } else { (1, 0) }
-            } else {
-                // 3 or more endpoints: choose two distinct endpoints at random.
-                let i0 = rng.gen_range(0, sz);
-                let mut i1 = rng.gen_range(0, sz);
-                while i0 == i1 {
-                    i1 = rng.gen_range(0, sz);
-                }
-                (i0, i1)
-            };
-
-            // Determine the the scores of each endpoint
-            let (addr0, ep0) = available.get_index(i0).unwrap();
-            let (load0, weight0) = (ep0.load(), ep0.weight());
-            let score0 = (load0 + 1) as f64 * (1.0 - weight0);
-
-            let (addr1, ep1) = available.get_index(i1).unwrap();
-            let (load1, weight1) = (ep1.load(), ep1.weight());
-            let score1 = (load1 + 1) as f64 * (1.0 - weight1);
-
-            if score0 <= score1 {
-                trace!(
-                    "dst: {} {}*{} (not {} {}*{})",
-                    addr0,
-                    load0,
-                    weight0,
-                    addr1,
-                    load1,
-                    weight1
-                );
-                Some(ep0)
-            } else {
-                trace!(
-                    "dst: {} {}*{} (not {} {}*{})",
-                    addr1,
-                    load1,
-                    weight1,
-                    addr0,
-                    load0,
-                    weight0
-                );
-                Some(ep1)
-            }
+        // Once the balancer has been dropped (i.e. because its route was evicted by a
+        // configuration reload) and all waiters have been satisfied, the dispatcher is no
+        // longer needed. Established connections are owned by their streams and are not
+        // affected.
+        if self.waiters_closed && self.waiters.is_empty() && self.dedicated_waiters.is_empty() &&
+            self.dedicated.is_empty()
+        {
+            debug!("{}: balancer dropped; stopping dispatcher", self.dst_name);
+            return Ok(Async::Ready(()));
         }
+
+        Ok(Async::NotReady)
     }
 }
 
@@ -444,13 +829,35 @@ struct Metrics {
     waiters: tacho::Gauge,
     poll_time: tacho::Timer,
     attempts: tacho::Counter,
+    attempts_per_connection: tacho::Stat,
+    retries: tacho::Counter,
+    retries_exhausted: tacho::Counter,
+    hedges: tacho::Counter,
+    hedges_exhausted: tacho::Counter,
+    hedge_wins: tacho::Counter,
     unavailable: tacho::Counter,
+    ejections: tacho::Counter,
+    health_checks: tacho::Counter,
     connects: tacho::Counter,
     timeouts: tacho::Counter,
+    tcp_timeouts: tacho::Counter,
+    tls_timeouts: tacho::Counter,
+    tls_errors: tacho::Counter,
     refused: tacho::Counter,
+    addr_unavailable: tacho::Counter,
     failures: tacho::Counter,
     connect_latency: tacho::Timer,
     connection_duration: tacho::Timer,
+    tls: TlsMetrics,
+    endpoint: tacho::Scope,
+    endpoints: HashMap<net::SocketAddr, EndpointGauges>,
+}
+
+struct EndpointGauges {
+    /// The effective weight of the endpoint, in parts per million.
+    weight: tacho::Gauge,
+    /// 1 if the endpoint is healthy, otherwise 0.
+    healthy: tacho::Gauge,
 }
 
 impl Metrics {
@@ -466,20 +873,45 @@ impl Metrics {
             waiters: base.gauge("waiters"),
             poll_time: base.timer_us("poll_time_us"),
             unavailable: base.counter("unavailable"),
+            ejections: ep.counter("ejections"),
+            health_checks: ep.counter("health_checks"),
             attempts: conn.counter("attempts"),
+            attempts_per_connection: conn.stat("attempts_per_connection"),
+            retries: conn.counter("retries"),
+            retries_exhausted: conn.counter("retries_exhausted"),
+            hedges: conn.counter("hedges"),
+            hedges_exhausted: conn.counter("hedges_exhausted"),
+            hedge_wins: conn.counter("hedge_wins"),
             connects: conn.counter("connects"),
             timeouts: conn.clone().labeled("cause", "timeout").counter("failure"),
+            tcp_timeouts: conn.clone().labeled("cause", "tcp_timeout").counter("failure"),
+            tls_timeouts: conn.clone().labeled("cause", "tls_timeout").counter("failure"),
+            tls_errors: conn.clone().labeled("cause", "tls_error").counter("failure"),
             refused: conn.clone().labeled("cause", "refused").counter("failure"),
+            addr_unavailable: conn.clone()
+                .labeled("cause", "addr_unavailable")
+                .counter("failure"),
             failures: conn.clone().labeled("cause", "other").counter("failure"),
             connect_latency: conn.timer_us("latency_us"),
             connection_duration: conn.timer_ms("duration_ms"),
+            tls: TlsMetrics::new(&conn.clone().prefixed("tls")),
+            endpoint: ep,
+            endpoints: HashMap::new(),
         }
     }
 
     fn failure(&self, err: &io::Error) {
+        match ConnectError::from_io(err) {
+            Some(&ConnectError::TcpTimeout) => return self.tcp_timeouts.incr(1),
+            Some(&ConnectError::TlsTimeout) => return self.tls_timeouts.incr(1),
+            Some(&ConnectError::TlsHandshake(_)) => return self.tls_errors.incr(1),
+            None => {}
+        }
         match err.kind() {
             io::ErrorKind::TimedOut => self.timeouts.incr(1),
             io::ErrorKind::ConnectionRefused => self.refused.incr(1),
+            // I.e. the local ephemeral or configured source ports are exhausted.
+            io::ErrorKind::AddrNotAvailable => self.addr_unavailable.incr(1),
             _ => self.failures.incr(1),
         }
     }
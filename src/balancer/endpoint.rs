@@ -1,18 +1,25 @@
+use super::EndpointMeta;
+use super::load::{self, PeakEwma, Throughput};
 use super::super::connection::{Connection as _Connection, ctx};
-use super::super::connector;
-use futures::{Future, Poll};
-use std::{io, net};
+use super::super::connector::{self, FailFast, HealthCheck, SlowStart};
+use futures::{Async, Future, Poll};
+use std::{cmp, io, net};
 use std::cell::{Ref, RefCell};
+use std::collections::VecDeque;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tacho;
 
 pub type Connection = _Connection<Ctx>;
 
-pub fn new(peer_addr: net::SocketAddr, weight: f64) -> Endpoint {
+pub fn new(peer_addr: net::SocketAddr, weight: f64, meta: EndpointMeta) -> Endpoint {
     Endpoint {
         peer_addr,
         weight,
+        effective_weight: weight,
+        available_since: Instant::now(),
+        ejections: 0,
+        meta,
         state: Rc::new(RefCell::new(State::default())),
     }
 }
@@ -24,9 +31,67 @@ pub struct State {
     pub consecutive_failures: usize,
     pub rx_bytes: usize,
     pub tx_bytes: usize,
+    /// Estimates the time to establish a connection to the endpoint.
+    pub connect_latency: PeakEwma,
+    /// Estimates the rate at which bytes are transferred with the endpoint.
+    pub throughput: Throughput,
+    /// The time and success of recent connection attempts, oldest first.
+    pub outcomes: VecDeque<(Instant, bool)>,
+    pub health: Health,
 }
 
+/// The health of an endpoint, as determined by active health checks.
+///
+/// Endpoints are considered healthy until probes show otherwise.
+pub struct Health {
+    pub healthy: bool,
+    /// Consecutive probes that contradict the current health state.
+    transitions: usize,
+    /// Indicates that a probe is in flight.
+    probing: bool,
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health {
+            healthy: true,
+            transitions: 0,
+            probing: false,
+        }
+    }
+}
+
+impl Health {
+    fn record(&mut self, success: bool, hc: &HealthCheck) {
+        if success == self.healthy {
+            self.transitions = 0;
+            return;
+        }
+        self.transitions += 1;
+        let threshold = if self.healthy {
+            hc.unhealthy_threshold
+        } else {
+            hc.healthy_threshold
+        };
+        if self.transitions >= threshold {
+            self.healthy = success;
+            self.transitions = 0;
+        }
+    }
+}
+
+/// Bounds the number of connection attempts retained to compute success rates.
+const MAX_OUTCOMES: usize = 1024;
+
 impl State {
+    fn record_outcome(&mut self, success: bool) {
+        if self.outcomes.len() == MAX_OUTCOMES {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back((Instant::now(), success));
+    }
+
+
     pub fn load(&self) -> usize {
         self.open_conns + self.pending_conns
     }
@@ -38,7 +103,16 @@ impl State {
 /// Represents a single concrete traffic destination
 pub struct Endpoint {
     peer_addr: net::SocketAddr,
+    /// The weight reported by service discovery.
     weight: f64,
+    /// The weight used for load balancing, which is reduced while the endpoint warms up.
+    effective_weight: f64,
+    /// When the endpoint most recently became available for new connections.
+    available_since: Instant,
+    /// The number of times the endpoint has been ejected since it was last healthy for
+    /// long enough to be forgiven.
+    ejections: u32,
+    meta: EndpointMeta,
     state: Rc<RefCell<State>>,
 }
 
@@ -51,32 +125,150 @@ impl Endpoint {
         self.state.borrow()
     }
 
-    // TODO we should be able to use throughput/bandwidth as well.
     pub fn load(&self) -> usize {
         self.state.borrow().load()
     }
 
+    /// Returns the rate at which bytes have recently been transferred with the endpoint,
+    /// in bytes per second, decayed over `decay`.
+    pub fn throughput(&self, now: Instant, decay: Duration) -> f64 {
+        let mut state = self.state.borrow_mut();
+        let bytes = state.rx_bytes + state.tx_bytes;
+        state.throughput.update(bytes, now, decay)
+    }
+
+    /// Sets the endpoint's relative capacity. Resolvers ensure that weights are finite
+    /// and non-negative.
     pub fn set_weight(&mut self, w: f64) {
-        assert!(0.0 <= w && w <= 1.0);
         self.weight = w;
     }
 
+    /// Returns the weight used for load balancing.
     pub fn weight(&self) -> f64 {
+        self.effective_weight
+    }
+
+    /// Returns the weight reported by service discovery, regardless of warm-up.
+    pub fn resolved_weight(&self) -> f64 {
         self.weight
     }
 
-    pub fn connect(&self, sock: connector::Connecting, duration: &tacho::Timer) -> Connecting {
+    /// Marks the endpoint as having just become available for new connections, so that
+    /// it is warmed up again and its prior failures are forgotten.
+    pub fn mark_available(&mut self) {
+        self.available_since = Instant::now();
+        let mut state = self.state.borrow_mut();
+        state.consecutive_failures = 0;
+        state.outcomes.clear();
+    }
+
+    /// Indicates whether the endpoint fails too often to be used for new connections.
+    pub fn is_outlier(&self, now: Instant, fail_fast: &FailFast) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.consecutive_failures >= fail_fast.max_consecutive_failures {
+            return true;
+        }
+        match fail_fast.success_rate {
+            None => false,
+            Some(ref sr) => {
+                while let Some(&(t, _)) = state.outcomes.front() {
+                    if t + sr.window >= now {
+                        break;
+                    }
+                    state.outcomes.pop_front();
+                }
+                let attempts = state.outcomes.len();
+                if attempts == 0 || attempts < sr.min_attempts {
+                    return false;
+                }
+                let successes = state.outcomes.iter().filter(|&&(_, ok)| ok).count();
+                (successes as f64 / attempts as f64) < sr.min_success_rate
+            }
+        }
+    }
+
+    /// Ejects the endpoint, returning the time until which it is ejected.
+    ///
+    /// The penalty doubles each time the endpoint is ejected, unless it had remained
+    /// available for at least the maximum penalty.
+    pub fn eject(&mut self, now: Instant, fail_fast: &FailFast) -> Instant {
+        if now > self.available_since && now - self.available_since >= fail_fast.max_penalty {
+            self.ejections = 0;
+        }
+        self.ejections += 1;
+        let penalty = if self.ejections > 31 {
+            fail_fast.max_penalty
+        } else {
+            fail_fast
+                .penalty
+                .checked_mul(1 << (self.ejections - 1))
+                .unwrap_or(fail_fast.max_penalty)
+        };
+        let penalty = cmp::min(penalty, fail_fast.max_penalty);
+        debug!(
+            "{}: ejected for {}s (ejection {})",
+            self.peer_addr,
+            penalty.as_secs(),
+            self.ejections
+        );
+        now + penalty
+    }
+
+    /// Updates the weight used for load balancing.
+    ///
+    /// During the slow start window after the endpoint becomes available, its weight ramps
+    /// linearly from a fraction of its resolved weight up to its resolved weight.
+    pub fn update_effective_weight(&mut self, now: Instant, slow_start: Option<SlowStart>) {
+        self.effective_weight = match slow_start {
+            None => self.weight,
+            Some(ss) => {
+                let elapsed = if now > self.available_since {
+                    now - self.available_since
+                } else {
+                    Duration::from_secs(0)
+                };
+                if elapsed >= ss.window {
+                    self.weight
+                } else {
+                    let progress = load::nanos(elapsed) / load::nanos(ss.window);
+                    self.weight * (ss.min_weight + (1.0 - ss.min_weight) * progress)
+                }
+            }
+        };
+    }
+
+    pub fn meta(&self) -> &EndpointMeta {
+        &self.meta
+    }
+
+    pub fn set_meta(&mut self, meta: EndpointMeta) {
+        self.meta = meta;
+    }
+
+    /// Tracks a connection to this endpoint.
+    ///
+    /// The connection is considered pending until it completes or, if it's dropped
+    /// first, is canceled. `latency_decay` controls how quickly the endpoint's connect
+    /// latency estimate forgets older connections.
+    pub fn connect(
+        &self,
+        sock: connector::Connecting,
+        latency_decay: Duration,
+        duration: &tacho::Timer,
+    ) -> Connecting {
         let conn = {
             let peer_addr = self.peer_addr;
             let state = self.state.clone();
             let duration = duration.clone();
+            let t0 = Instant::now();
             debug!("{}: connecting", peer_addr);
             sock.then(move |res| match res {
                 Err(e) => {
                     error!("{}: connection failed: {}", peer_addr, e);
                     let mut s = state.borrow_mut();
                     s.consecutive_failures += 1;
-                    s.pending_conns -= 1;
+                    s.connect_latency.observe_failure(t0.elapsed());
+                    s.record_outcome(false);
                     Err(e)
                 }
                 Ok(sock) => {
@@ -84,8 +276,9 @@ impl Endpoint {
                     {
                         let mut s = state.borrow_mut();
                         s.consecutive_failures = 0;
-                        s.pending_conns -= 1;
                         s.open_conns += 1;
+                        s.connect_latency.observe(t0.elapsed(), latency_decay);
+                        s.record_outcome(true);
                     }
                     let ctx = Ctx {
                         state,
@@ -97,22 +290,90 @@ impl Endpoint {
             })
         };
 
-        let mut state = self.state.borrow_mut();
-        state.pending_conns += 1;
-        Connecting(Box::new(conn))
+        self.state.borrow_mut().pending_conns += 1;
+        Connecting {
+            conn: Box::new(conn),
+            state: Some(self.state.clone()),
+        }
     }
 
     pub fn is_idle(&self) -> bool {
         self.state.borrow().is_idle()
     }
+
+    pub fn is_healthy(&self) -> bool {
+        self.state.borrow().health.healthy
+    }
+
+    /// Considers the endpoint healthy, i.e. because health checks have been disabled.
+    pub fn reset_health(&self) {
+        self.state.borrow_mut().health = Health::default();
+    }
+
+    /// Indicates that a health check probe of this endpoint is in flight.
+    pub fn is_probing(&self) -> bool {
+        self.state.borrow().health.probing
+    }
+
+    /// Tracks a health check probe, returning a task that records its result.
+    pub fn check_health<F>(&self, probe: F, hc: &HealthCheck) -> Box<Future<Item = (), Error = ()>>
+    where
+        F: Future<Item = (), Error = io::Error> + 'static,
+    {
+        self.state.borrow_mut().health.probing = true;
+        let peer_addr = self.peer_addr;
+        let state = self.state.clone();
+        let hc = hc.clone();
+        let f = probe.then(move |res| {
+            if let Err(ref e) = res {
+                debug!("{}: health check failed: {}", peer_addr, e);
+            }
+            let mut state = state.borrow_mut();
+            let health = &mut state.health;
+            let was_healthy = health.healthy;
+            health.probing = false;
+            health.record(res.is_ok(), &hc);
+            if health.healthy != was_healthy {
+                info!(
+                    "{}: {}",
+                    peer_addr,
+                    if health.healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+            Ok(())
+        });
+        Box::new(f)
+    }
 }
 
-pub struct Connecting(Box<Future<Item = Connection, Error = io::Error> + 'static>);
+pub struct Connecting {
+    conn: Box<Future<Item = Connection, Error = io::Error> + 'static>,
+    /// Taken once the connection is no longer pending.
+    state: Option<Rc<RefCell<State>>>,
+}
+impl Connecting {
+    fn done(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.borrow_mut().pending_conns -= 1;
+        }
+    }
+}
 impl Future for Connecting {
     type Item = Connection;
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Connection, io::Error> {
-        self.0.poll()
+        let res = self.conn.poll();
+        if let Ok(Async::NotReady) = res {
+            return res;
+        }
+        self.done();
+        res
+    }
+}
+/// Canceled connections are no longer pending.
+impl Drop for Connecting {
+    fn drop(&mut self) {
+        self.done();
     }
 }
 
@@ -1,7 +1,8 @@
 use super::Balancer;
 use super::super::Path;
-use super::super::connector::{ConfigError, ConnectorFactory};
+use super::super::connector::{ConfigError, ConnectorConfig, ConnectorFactory};
 use super::super::resolver::Resolve;
+use futures::Future;
 use std::cell::RefCell;
 use std::rc::Rc;
 use tacho;
@@ -22,6 +23,33 @@ impl BalancerFactory {
         }
     }
 
+    /// Returns the connector configuration that applies to the given name.
+    pub fn connector_config(&self, dst_name: &Path) -> ConnectorConfig {
+        self.connector_factory.borrow().connector_config(dst_name)
+    }
+
+    /// Replaces the connector factory used to build new balancers.
+    ///
+    /// Returns false if the connectors of existing balancers cannot be updated by the new
+    /// factory, in which case they should be rebuilt.
+    pub fn set_connector_factory(&self, cf: ConnectorFactory) -> bool {
+        let adopted = cf.adopt(&self.connector_factory.borrow());
+        *self.connector_factory.borrow_mut() = cf;
+        adopted
+    }
+
+    /// Replaces the configurations of a dynamic connector factory, updating the
+    /// connectors of existing balancers.
+    pub fn update_connector_configs(&self, configs: &[ConnectorConfig]) -> Result<usize, ConfigError> {
+        self.connector_factory.borrow().update_configs(configs)
+    }
+
+    /// Returns a task that reloads connector configurations from a watched file, if one
+    /// is configured.
+    pub fn watch(&self, timer: &Timer) -> Option<Box<Future<Item = (), Error = ()>>> {
+        self.connector_factory.borrow().watch(timer)
+    }
+
     pub fn mk_balancer(
         &self,
         reactor: &Handle,
@@ -29,13 +57,14 @@ impl BalancerFactory {
         dst_name: &Path,
         resolve: Resolve,
     ) -> Result<Balancer, ConfigError> {
-        let connector = self.connector_factory.borrow().mk_connector(dst_name)?;
+        let (connector, updates) = self.connector_factory.borrow().mk_connector(dst_name)?;
         let metrics = self.metrics.clone().labeled("dst", dst_name);
         Ok(super::new(
             reactor,
             timer,
             dst_name,
             connector,
+            updates,
             resolve,
             &metrics,
         ))
@@ -0,0 +1,112 @@
+//! Estimates of endpoint load, beyond simple connection counts.
+
+use std::time::{Duration, Instant};
+
+/// A peak-sensitive, exponentially-weighted moving average of connect latencies.
+///
+/// Latencies higher than the estimate replace it immediately, while lower latencies are
+/// averaged in, so that an endpoint that slows down is avoided quickly but must recover
+/// gradually. Between observations the estimate decays toward zero so that endpoints that
+/// have not been used recently are eventually tried again.
+pub struct PeakEwma {
+    /// The estimated latency, in nanoseconds.
+    estimate: f64,
+    updated: Instant,
+}
+
+impl Default for PeakEwma {
+    fn default() -> PeakEwma {
+        PeakEwma {
+            estimate: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl PeakEwma {
+    /// Updates the estimate with an observed latency.
+    ///
+    /// `decay` controls how long it takes for older observations to lose influence.
+    pub fn observe(&mut self, latency: Duration, decay: Duration) {
+        let now = Instant::now();
+        let latency = nanos(latency);
+        self.estimate = if latency > self.estimate {
+            latency
+        } else {
+            let w = weight(now - self.updated, decay);
+            self.estimate * w + latency * (1.0 - w)
+        };
+        self.updated = now;
+    }
+
+    /// Updates the estimate with the time spent on a failed attempt, which may only
+    /// increase the estimate, since failing quickly does not make an endpoint faster.
+    pub fn observe_failure(&mut self, elapsed: Duration) {
+        let elapsed = nanos(elapsed);
+        if elapsed > self.estimate {
+            self.estimate = elapsed;
+            self.updated = Instant::now();
+        }
+    }
+
+    /// Returns the estimated latency in nanoseconds, decayed since the last observation.
+    pub fn get(&self, now: Instant, decay: Duration) -> f64 {
+        if now <= self.updated {
+            return self.estimate;
+        }
+        self.estimate * weight(now - self.updated, decay)
+    }
+}
+
+/// An exponentially-weighted moving average of the rate at which bytes are transferred.
+///
+/// The rate is sampled whenever it is read, from the total number of bytes transferred.
+pub struct Throughput {
+    /// The estimated rate, in bytes per second.
+    rate: f64,
+    bytes: usize,
+    updated: Instant,
+}
+
+impl Default for Throughput {
+    fn default() -> Throughput {
+        Throughput {
+            rate: 0.0,
+            bytes: 0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl Throughput {
+    /// Samples the rate given the total number of bytes transferred so far, returning
+    /// the estimate in bytes per second.
+    ///
+    /// `decay` controls how long it takes for older samples to lose influence.
+    pub fn update(&mut self, bytes: usize, now: Instant, decay: Duration) -> f64 {
+        if now <= self.updated {
+            return self.rate;
+        }
+        let elapsed = now - self.updated;
+        let secs = nanos(elapsed) / 1_000_000_000.0;
+        let rate = bytes.saturating_sub(self.bytes) as f64 / secs;
+        let w = weight(elapsed, decay);
+        self.rate = self.rate * w + rate * (1.0 - w);
+        self.bytes = bytes;
+        self.updated = now;
+        self.rate
+    }
+}
+
+/// The weight of a value that was observed `elapsed` ago.
+fn weight(elapsed: Duration, decay: Duration) -> f64 {
+    let decay = nanos(decay);
+    if decay == 0.0 {
+        return 0.0;
+    }
+    (-nanos(elapsed) / decay).exp()
+}
+
+pub fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1_000_000_000.0 + f64::from(d.subsec_nanos())
+}
@@ -1,43 +1,90 @@
 use super::Path;
-use super::connector::Connector;
+use super::connector::{Connector, ConnectorUpdates, FailFast, SlowStart};
 use super::resolver::Resolve;
 use futures::{Async, Future, Poll, unsync};
 use ordermap::OrderMap;
 use std::{cmp, io, net};
 use std::collections::VecDeque;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use tacho;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
+mod budget;
 mod dispatcher;
 mod endpoint;
 mod factory;
+mod load;
+mod strategy;
 
 pub use self::endpoint::{Connection as EndpointConnection, Ctx as EndpointCtx};
 use self::endpoint::Endpoint;
 pub use self::factory::BalancerFactory;
 
-type Waiter = unsync::oneshot::Sender<endpoint::Connection>;
+/// Describes the inbound connection on whose behalf an outbound connection is requested.
+#[derive(Clone, Debug, Default)]
+pub struct SrcMeta {
+    /// The address of the inbound connection's peer.
+    pub addr: Option<net::SocketAddr>,
+
+    /// The server name that the inbound connection requested via SNI, if any.
+    pub sni: Option<String>,
+}
+
+/// A request for an outbound connection.
+pub struct Waiter {
+    src: SrcMeta,
+    rsp: unsync::oneshot::Sender<io::Result<endpoint::Connection>>,
+}
+
+impl Waiter {
+    pub fn src(&self) -> &SrcMeta {
+        &self.src
+    }
+
+    /// Satisfies the request, returning the connection if the requester has gone away.
+    pub fn send(self, conn: endpoint::Connection) -> Result<(), endpoint::Connection> {
+        self.rsp.send(Ok(conn)).map_err(
+            |rsp| rsp.expect("the sent connection is returned"),
+        )
+    }
+
+    /// Fails the request, i.e. because connection attempts may no longer be retried.
+    pub fn fail(self, e: io::Error) {
+        let _ = self.rsp.send(Err(e));
+    }
+}
 
 /// A weighted concrete destination address.
 #[derive(Clone, Debug)]
 pub struct WeightedAddr {
     pub addr: ::std::net::SocketAddr,
     pub weight: f64,
+    pub meta: EndpointMeta,
 }
 
 impl WeightedAddr {
-    pub fn new(addr: net::SocketAddr, weight: f64) -> WeightedAddr {
-        WeightedAddr { addr, weight }
+    pub fn new(addr: net::SocketAddr, weight: f64, meta: EndpointMeta) -> WeightedAddr {
+        WeightedAddr { addr, weight, meta }
     }
 }
 
+/// Describes a concrete destination address, as reported by service discovery.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EndpointMeta {
+    /// The authority that the endpoint serves, i.e. `host:port`.
+    pub authority: Option<String>,
+
+    /// The name of the node on which the endpoint runs.
+    pub node_name: Option<String>,
+}
+
 pub fn new(
     reactor: &Handle,
     timer: &Timer,
     dst: &Path,
     connector: Connector,
+    connector_updates: ConnectorUpdates,
     resolve: Resolve,
     metrics: &tacho::Scope,
 ) -> Balancer {
@@ -47,6 +94,7 @@ pub fn new(
         timer.clone(),
         dst.clone(),
         connector,
+        connector_updates,
         resolve,
         rx,
         Endpoints::default(),
@@ -59,17 +107,20 @@ pub fn new(
 #[derive(Clone)]
 pub struct Balancer(unsync::mpsc::UnboundedSender<Waiter>);
 impl Balancer {
-    /// Obtains a connection to the destination.
-    pub fn connect(&self) -> Connect {
+    /// Obtains a connection to the destination on behalf of an inbound connection.
+    pub fn connect(&self, src: SrcMeta) -> Connect {
         let (tx, rx) = unsync::oneshot::channel();
-        let result = unsync::mpsc::UnboundedSender::unbounded_send(&self.0, tx)
+        let waiter = Waiter { src, rsp: tx };
+        let result = unsync::mpsc::UnboundedSender::unbounded_send(&self.0, waiter)
             .map_err(|_| io::Error::new(io::ErrorKind::Other, "lost dispatcher"))
             .map(|_| rx);
         Connect(Some(result))
     }
 }
 
-pub struct Connect(Option<io::Result<unsync::oneshot::Receiver<endpoint::Connection>>>);
+type ConnectRsp = unsync::oneshot::Receiver<io::Result<endpoint::Connection>>;
+
+pub struct Connect(Option<io::Result<ConnectRsp>>);
 impl Future for Connect {
     type Item = endpoint::Connection;
     type Error = io::Error;
@@ -79,7 +130,7 @@ impl Future for Connect {
         )?;
         match recv.poll() {
             Err(_) => Err(io::Error::new(io::ErrorKind::Interrupted, "canceled")),
-            Ok(Async::Ready(conn)) => Ok(Async::Ready(conn)),
+            Ok(Async::Ready(conn)) => Ok(Async::Ready(conn?)),
             Ok(Async::NotReady) => {
                 self.0 = Some(Ok(recv));
                 Ok(Async::NotReady)
@@ -89,6 +140,7 @@ impl Future for Connect {
 }
 
 pub type EndpointMap = OrderMap<net::SocketAddr, Endpoint>;
+/// Ejected endpoints, with the time until which each is ejected.
 pub type FailedMap = OrderMap<net::SocketAddr, (Instant, Endpoint)>;
 
 #[derive(Default)]
@@ -116,37 +168,75 @@ impl Endpoints {
         &self.retired
     }
 
-    pub fn update_failed(&mut self, max_failures: usize, penalty: Duration) {
-        let mut available = VecDeque::with_capacity(self.failed.len());
+    /// Ejects endpoints that fail too often or fail health checks, and restores ejected
+    /// endpoints that are healthy and whose penalties have expired. Returns the number
+    /// of endpoints ejected.
+    ///
+    /// No more than `max_ejection_percent` of endpoints (rounded up, but always leaving at
+    /// least one) are ejected at once, so that a widespread failure does not leave too few
+    /// endpoints to serve connections.
+    pub fn update_failed(&mut self, fail_fast: &FailFast) -> usize {
+        let now = Instant::now();
+        let mut revived = VecDeque::with_capacity(self.failed.len());
         let mut failed = VecDeque::with_capacity(self.failed.len());
 
-        for (_, ep) in self.available.drain(..) {
-            if ep.state().consecutive_failures < max_failures {
-                available.push_back(ep);
+        for (_, (until, mut ep)) in self.failed.drain(..) {
+            if until <= now && ep.is_healthy() {
+                ep.mark_available();
+                revived.push_back(ep);
             } else {
-                failed.push_back((Instant::now(), ep));
+                failed.push_back((until, ep));
             }
         }
 
-        for (_, (start, ep)) in self.failed.drain(..) {
-            if start + penalty <= Instant::now() {
+        let total = self.available.len() + revived.len() + failed.len();
+        let max_ejected = max_ejected(total, fail_fast.max_ejection_percent);
+
+        let mut ejected = 0;
+        let mut available = VecDeque::with_capacity(self.available.len() + revived.len());
+        for (_, mut ep) in self.available.drain(..) {
+            if failed.len() >= max_ejected {
                 available.push_back(ep);
+            } else if !ep.is_healthy() {
+                // Unhealthy endpoints are restored as soon as they are healthy again.
+                debug!("{}: ejected until healthy", ep.peer_addr());
+                failed.push_back((now, ep));
+                ejected += 1;
+            } else if ep.is_outlier(now, fail_fast) {
+                let until = ep.eject(now, fail_fast);
+                failed.push_back((until, ep));
+                ejected += 1;
             } else {
-                failed.push_back((start, ep));
+                available.push_back(ep);
             }
         }
+        available.extend(revived);
 
-        if available.is_empty() {
-            while let Some((_, ep)) = failed.pop_front() {
-                self.available.insert(ep.peer_addr(), ep);
-            }
-        } else {
-            while let Some(ep) = available.pop_front() {
-                self.available.insert(ep.peer_addr(), ep);
-            }
-            while let Some((since, ep)) = failed.pop_front() {
-                self.failed.insert(ep.peer_addr(), (since, ep));
-            }
+        while let Some(ep) = available.pop_front() {
+            self.available.insert(ep.peer_addr(), ep);
+        }
+        while let Some((until, ep)) = failed.pop_front() {
+            self.failed.insert(ep.peer_addr(), (until, ep));
+        }
+        ejected
+    }
+
+    /// Considers all endpoints healthy, i.e. because health checks have been disabled.
+    pub fn reset_health(&self) {
+        for ep in self.available.values().chain(self.retired.values()) {
+            ep.reset_health();
+        }
+        for &(_, ref ep) in self.failed.values() {
+            ep.reset_health();
+        }
+    }
+
+    /// Updates the weights used to balance load over available endpoints, i.e. as
+    /// endpoints warm up.
+    pub fn update_weights(&mut self, slow_start: Option<SlowStart>) {
+        let now = Instant::now();
+        for ep in self.available.values_mut() {
+            ep.update_effective_weight(now, slow_start);
         }
     }
 
@@ -167,7 +257,7 @@ impl Endpoints {
     /// Checks active endpoints.
     fn check_available(
         &mut self,
-        dsts: &OrderMap<net::SocketAddr, f64>,
+        dsts: &OrderMap<net::SocketAddr, WeightedAddr>,
         temp: &mut VecDeque<Endpoint>,
     ) {
         for (addr, ep) in self.available.drain(..) {
@@ -192,7 +282,7 @@ impl Endpoints {
     /// retired if still active, or dropped if inactive.
     fn check_retired(
         &mut self,
-        dsts: &OrderMap<net::SocketAddr, f64>,
+        dsts: &OrderMap<net::SocketAddr, WeightedAddr>,
         temp: &mut VecDeque<Endpoint>,
     ) {
         for (addr, ep) in self.retired.drain(..) {
@@ -212,11 +302,11 @@ impl Endpoints {
     }
 
     /// Checks failed endpoints.
-    fn check_failed(&mut self, dsts: &OrderMap<net::SocketAddr, f64>) {
+    fn check_failed(&mut self, dsts: &OrderMap<net::SocketAddr, WeightedAddr>) {
         let mut temp = VecDeque::with_capacity(self.failed.len());
-        for (addr, (since, ep)) in self.failed.drain(..) {
+        for (addr, (until, ep)) in self.failed.drain(..) {
             if dsts.contains_key(&addr) {
-                temp.push_back((since, ep));
+                temp.push_back((until, ep));
             } else if ep.is_idle() {
                 drop(ep);
             } else {
@@ -230,29 +320,69 @@ impl Endpoints {
         }
     }
 
-    fn update_available_from_new(&mut self, mut dsts: OrderMap<net::SocketAddr, f64>) {
-        // Add new endpoints or update the base weights of existing endpoints.
+    fn update_available_from_new(&mut self, mut dsts: OrderMap<net::SocketAddr, WeightedAddr>) {
+        // Add new endpoints or update the base weights and metadata of existing
+        // endpoints.
         //let metrics = self.endpoint_metrics.clone();
-        for (addr, weight) in dsts.drain(..) {
+        for (addr, dst) in dsts.drain(..) {
             if let Some(&mut (_, ref mut ep)) = self.failed.get_mut(&addr) {
-                ep.set_weight(weight);
+                ep.set_weight(dst.weight);
+                ep.set_meta(dst.meta);
                 continue;
             }
 
             if let Some(ep) = self.available.get_mut(&addr) {
-                ep.set_weight(weight);
+                ep.set_weight(dst.weight);
+                ep.set_meta(dst.meta);
                 continue;
             }
 
-            self.available.insert(addr, endpoint::new(addr, weight));
+            self.available.insert(addr, endpoint::new(addr, dst.weight, dst.meta));
         }
     }
 
-    fn dsts_by_addr(dsts: &[WeightedAddr]) -> OrderMap<net::SocketAddr, f64> {
+    fn dsts_by_addr(dsts: &[WeightedAddr]) -> OrderMap<net::SocketAddr, WeightedAddr> {
         let mut by_addr = OrderMap::with_capacity(dsts.len());
-        for &WeightedAddr { addr, weight } in dsts {
-            by_addr.insert(addr, weight);
+        for dst in dsts {
+            by_addr.insert(dst.addr, dst.clone());
         }
         by_addr
     }
 }
+
+/// The number of endpoints that may be ejected at once: `percent` of `total`, rounded up
+/// so that small clusters may still eject outliers, but never every endpoint.
+fn max_ejected(total: usize, percent: usize) -> usize {
+    let max = (total * percent + 99) / 100;
+    cmp::min(max, total.saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::max_ejected;
+
+    #[test]
+    fn max_ejected_small_clusters() {
+        assert_eq!(max_ejected(0, 50), 0);
+        assert_eq!(max_ejected(1, 50), 0);
+        assert_eq!(max_ejected(1, 100), 0);
+        assert_eq!(max_ejected(2, 10), 1);
+        assert_eq!(max_ejected(2, 50), 1);
+        assert_eq!(max_ejected(2, 100), 1);
+        assert_eq!(max_ejected(3, 50), 2);
+        assert_eq!(max_ejected(3, 100), 2);
+    }
+
+    #[test]
+    fn max_ejected_large_clusters() {
+        assert_eq!(max_ejected(10, 50), 5);
+        assert_eq!(max_ejected(10, 15), 2);
+        assert_eq!(max_ejected(100, 50), 50);
+        assert_eq!(max_ejected(100, 100), 99);
+    }
+
+    #[test]
+    fn max_ejected_disabled() {
+        assert_eq!(max_ejected(10, 0), 0);
+    }
+}
@@ -0,0 +1,503 @@
+//! Strategies for selecting the endpoint to which a new connection is established.
+
+use super::{EndpointMap, SrcMeta};
+use super::endpoint::Endpoint;
+use super::load;
+use super::super::connector::{HashKey, LoadBalancerConfig, LoadConfig};
+use rand::{self, Rng};
+use std::cell::{Cell, RefCell};
+use std::net;
+use std::time::{Duration, Instant};
+
+/// The number of times a strategy may select a failed endpoint for a retry before
+/// another endpoint is chosen at random.
+const RETRY_SELECTIONS: usize = 3;
+
+/// Selects an endpoint from those available for new connections.
+pub trait Strategy {
+    /// Returns `None` if no endpoints are available.
+    ///
+    /// `src` describes the inbound connection on whose behalf the connection is
+    /// established, if any.
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        src: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint>;
+
+    /// Selects an endpoint other than `failed` to retry a failed connection attempt,
+    /// falling back to `failed` if no other endpoint is available.
+    ///
+    /// Strategies that choose randomly are given a few chances to choose another
+    /// endpoint; otherwise, i.e. if the strategy always chooses the same endpoint for
+    /// `src`, another is chosen uniformly.
+    fn select_retry<'e>(
+        &self,
+        available: &'e EndpointMap,
+        src: Option<&SrcMeta>,
+        failed: &net::SocketAddr,
+    ) -> Option<&'e Endpoint> {
+        for _ in 0..RETRY_SELECTIONS {
+            match self.select(available, src) {
+                Some(ep) if ep.peer_addr() == *failed => {}
+                selected => return selected,
+            }
+        }
+        let others = available
+            .iter()
+            .filter(|&(addr, ep)| addr != failed && is_eligible(ep))
+            .map(|(_, ep)| ep)
+            .collect::<Vec<_>>();
+        match rand::thread_rng().choose(&others) {
+            Some(ep) => Some(*ep),
+            None => self.select(available, src),
+        }
+    }
+
+    /// Indicates that the endpoint depends on the inbound connection, so that each
+    /// inbound connection must be given a connection established specifically for it.
+    fn has_affinity(&self) -> bool {
+        false
+    }
+}
+
+pub fn new(config: &LoadBalancerConfig) -> Box<Strategy> {
+    match *config {
+        LoadBalancerConfig::P2c(ref p2c) => {
+            let load = match p2c.load {
+                None |
+                Some(LoadConfig::Connections) => Load::Connections,
+                Some(LoadConfig::PeakEwma(ref c)) => {
+                    Load::PeakEwma(config.latency_decay(), load::nanos(c.default_latency()))
+                }
+                Some(LoadConfig::Throughput(ref c)) => {
+                    Load::Throughput(c.decay(), c.bytes_per_connection.unwrap_or(0) as f64)
+                }
+            };
+            Box::new(PowerOfTwoChoices(load))
+        }
+        LoadBalancerConfig::RoundRobin => Box::new(RoundRobin(Cell::new(0))),
+        LoadBalancerConfig::WeightedRandom => Box::new(WeightedRandom),
+        LoadBalancerConfig::LeastConnections => Box::new(LeastConnections),
+        LoadBalancerConfig::RingHash(ref c) => {
+            let key = c.key.clone().unwrap_or(HashKey::SourceIp);
+            Box::new(RingHash::new(key, c.virtual_nodes()))
+        }
+    }
+}
+
+/// Scores an endpoint by its load, adjusted by its weight. Lower scores are preferred.
+fn score(ep: &Endpoint) -> f64 {
+    weighted((ep.load() + 1) as f64, ep)
+}
+
+/// Adjusts a cost by an endpoint's weight, which is treated as its relative capacity,
+/// i.e. an endpoint with twice the weight may bear twice the load.
+fn weighted(cost: f64, ep: &Endpoint) -> f64 {
+    cost / ep.weight()
+}
+
+/// Indicates whether an endpoint may receive new connections. Endpoints with a weight of
+/// zero receive no new connections.
+fn is_eligible(ep: &Endpoint) -> bool {
+    ep.weight() > 0.0
+}
+
+/// The cost of an endpoint with the given latency estimate and open and pending
+/// connections, before its weight is considered.
+///
+/// An endpoint without an estimate, i.e. one that has never connected or that has been
+/// idle, would otherwise cost nothing however many connections are pending to it, so
+/// that a new or blackholing endpoint would attract every connection.
+fn peak_ewma_cost(latency: f64, default_latency: f64, load: usize) -> f64 {
+    latency.max(default_latency) * (load + 1) as f64
+}
+
+/// Estimates the load of an endpoint.
+pub enum Load {
+    /// The number of open and pending connections.
+    Connections,
+
+    /// The endpoint's connect latency estimate, decayed over the given duration, scaled
+    /// by its open and pending connections. Open connections are this proxy's analogue
+    /// of the outstanding requests that linkerd and Finagle count.
+    ///
+    /// Estimates below the given default latency, in nanoseconds, are raised to it, so
+    /// that endpoints that have not connected recently are not free.
+    PeakEwma(Duration, f64),
+
+    /// The rate at which bytes are transferred with the endpoint, decayed over the given
+    /// duration, plus the given number of bytes per second for each open and pending
+    /// connection.
+    Throughput(Duration, f64),
+}
+
+impl Load {
+    fn score(&self, ep: &Endpoint, now: Instant) -> f64 {
+        match *self {
+            Load::Connections => score(ep),
+            Load::PeakEwma(decay, default_latency) => {
+                let latency = ep.state().connect_latency.get(now, decay);
+                weighted(peak_ewma_cost(latency, default_latency, ep.load()), ep)
+            }
+            Load::Throughput(decay, per_conn) => {
+                let rate = ep.throughput(now, decay);
+                weighted(rate + per_conn * ep.load() as f64 + 1.0, ep)
+            }
+        }
+    }
+}
+
+/// Selects an endpoint using the power of two choices.
+///
+/// Two endpoints are chosen randomly and the lesser-loaded endpoint is selected.
+pub struct PowerOfTwoChoices(Load);
+
+impl Strategy for PowerOfTwoChoices {
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        _: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint> {
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        let eligible = available
+            .values()
+            .enumerate()
+            .filter(|&(_, ep)| is_eligible(ep))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        match eligible.len() {
+            0 => None,
+            1 => {
+                // One endpoint, use it.
+                available.get_index(eligible[0]).map(|(_, ep)| ep)
+            }
+            sz => {
+                // Pick 2 candidate indices.
+                let (i0, i1) = if sz == 2 {
+                    if rng.gen::<bool>() { (0, 1) } else { (1, 0) }
+                } else {
+                    // 3 or more endpoints: choose two distinct endpoints at random.
+                    let i0 = rng.gen_range(0, sz);
+                    let mut i1 = rng.gen_range(0, sz);
+                    while i0 == i1 {
+                        i1 = rng.gen_range(0, sz);
+                    }
+                    (i0, i1)
+                };
+
+                // Determine the the scores of each endpoint
+                let (addr0, ep0) = available.get_index(eligible[i0]).unwrap();
+                let score0 = self.0.score(ep0, now);
+
+                let (addr1, ep1) = available.get_index(eligible[i1]).unwrap();
+                let score1 = self.0.score(ep1, now);
+
+                if score0 <= score1 {
+                    trace!("dst: {} {} (not {} {})", addr0, score0, addr1, score1);
+                    Some(ep0)
+                } else {
+                    trace!("dst: {} {} (not {} {})", addr1, score1, addr0, score0);
+                    Some(ep1)
+                }
+            }
+        }
+    }
+}
+
+/// Selects each endpoint with a positive weight in turn, regardless of load.
+pub struct RoundRobin(Cell<usize>);
+
+impl Strategy for RoundRobin {
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        _: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint> {
+        for _ in 0..available.len() {
+            let i = self.0.get() % available.len();
+            self.0.set(i + 1);
+            let (_, ep) = available.get_index(i).unwrap();
+            if is_eligible(ep) {
+                return Some(ep);
+            }
+        }
+        None
+    }
+}
+
+/// Selects endpoints randomly, in proportion to their weights, regardless of load.
+pub struct WeightedRandom;
+
+impl Strategy for WeightedRandom {
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        _: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint> {
+        let total = available.values().fold(0.0, |sum, ep| sum + ep.weight());
+        if total <= 0.0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen::<f64>() * total;
+        let mut last = None;
+        for ep in available.values() {
+            if !is_eligible(ep) {
+                continue;
+            }
+            if point < ep.weight() {
+                return Some(ep);
+            }
+            point -= ep.weight();
+            last = Some(ep);
+        }
+        // Rounding errors may leave `point` just beyond the last endpoint's weight.
+        last
+    }
+}
+
+/// Selects the least-loaded endpoint, considering every available endpoint with a
+/// positive weight.
+///
+/// Ties are broken randomly so that connections are not concentrated on the first
+/// endpoints.
+pub struct LeastConnections;
+
+impl Strategy for LeastConnections {
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        _: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint> {
+        let mut rng = rand::thread_rng();
+        let mut selected = None;
+        let mut ties = 0;
+        for ep in available.values().filter(|ep| is_eligible(ep)) {
+            let s = score(ep);
+            match selected {
+                Some((min, _)) if s > min => continue,
+                Some((min, _)) if s == min => {
+                    // Choose uniformly among tied endpoints (reservoir sampling).
+                    ties += 1;
+                    if rng.gen_range(0, ties) == 0 {
+                        selected = Some((s, ep));
+                    }
+                }
+                _ => {
+                    ties = 1;
+                    selected = Some((s, ep));
+                }
+            }
+        }
+        selected.map(|(_, ep)| ep)
+    }
+}
+
+/// Selects endpoints by consistent hashing of a property of the inbound connection, so
+/// that connections from the same client are sent to the same endpoint.
+///
+/// Each endpoint is placed on a hash ring at a number of points proportional to its
+/// resolved weight. When endpoints are added or removed, only the keys on the arcs they
+/// gain or lose are remapped. Effective weights are not used, so that clients are not
+/// remapped while endpoints warm up.
+pub struct RingHash {
+    key: HashKey,
+    virtual_nodes: usize,
+    ring: RefCell<Ring>,
+}
+
+#[derive(Default)]
+struct Ring {
+    /// Identifies the addresses and resolved weights of the endpoints from which the
+    /// ring was built, regardless of their order.
+    fingerprint: Option<(usize, u64)>,
+    points: Vec<(u64, net::SocketAddr)>,
+}
+
+impl RingHash {
+    fn new(key: HashKey, virtual_nodes: usize) -> RingHash {
+        RingHash {
+            key,
+            virtual_nodes,
+            ring: RefCell::new(Ring::default()),
+        }
+    }
+
+    /// Hashes the configured property of the inbound connection, falling back to its
+    /// source IP if the property is not available.
+    fn hash_src(&self, src: Option<&SrcMeta>) -> Option<u64> {
+        let src = src?;
+        if let HashKey::Sni = self.key {
+            if let Some(ref sni) = src.sni {
+                let mut h = StableHash::new();
+                h.write(sni.as_bytes());
+                return Some(h.finish());
+            }
+        }
+        src.addr.map(|a| {
+            let mut h = StableHash::new();
+            h.write_ip(&a.ip());
+            h.finish()
+        })
+    }
+
+    /// Rebuilds the ring if endpoints have been added or removed, or their resolved
+    /// weights have changed.
+    fn update_ring(&self, available: &EndpointMap) {
+        let fingerprint = available.iter().fold((0, 0u64), |(n, sum), (addr, ep)| {
+            let mut h = StableHash::new();
+            h.write_addr(addr);
+            h.write_u64(ep.resolved_weight().to_bits());
+            (n + 1, sum.wrapping_add(h.finish()))
+        });
+        let mut ring = self.ring.borrow_mut();
+        if ring.fingerprint == Some(fingerprint) {
+            return;
+        }
+
+        let eligible = available
+            .iter()
+            .filter(|&(_, ep)| ep.resolved_weight() > 0.0)
+            .collect::<Vec<_>>();
+        let n = eligible.len() as f64;
+        let total = eligible.iter().fold(0.0, |sum, &(_, ep)| sum + ep.resolved_weight());
+        let mut points = Vec::new();
+        for (addr, ep) in eligible {
+            // An endpoint of average weight is placed at `virtual_nodes` points.
+            let share = ep.resolved_weight() / total;
+            let count = (share * n * self.virtual_nodes as f64).round().max(1.0) as usize;
+            for i in 0..count {
+                let mut h = StableHash::new();
+                h.write_addr(addr);
+                h.write_u64(i as u64);
+                points.push((h.finish(), *addr));
+            }
+        }
+        points.sort();
+        trace!("rebuilt hash ring with {} points", points.len());
+        *ring = Ring {
+            fingerprint: Some(fingerprint),
+            points,
+        };
+    }
+}
+
+impl Strategy for RingHash {
+    fn select<'e>(
+        &self,
+        available: &'e EndpointMap,
+        src: Option<&SrcMeta>,
+    ) -> Option<&'e Endpoint> {
+        if available.is_empty() {
+            return None;
+        }
+        self.update_ring(available);
+        let ring = self.ring.borrow();
+        if ring.points.is_empty() {
+            return None;
+        }
+        // Connections without a key are spread randomly.
+        let h = self.hash_src(src).unwrap_or_else(|| rand::thread_rng().gen());
+        let i = match ring.points.binary_search_by(|&(p, _)| p.cmp(&h)) {
+            Ok(i) => i,
+            Err(i) if i == ring.points.len() => 0,
+            Err(i) => i,
+        };
+        available.get(&ring.points[i].1)
+    }
+
+    fn has_affinity(&self) -> bool {
+        true
+    }
+}
+
+/// A 64-bit FNV-1a hash, finalized with MurmurHash3's mixer so that similar keys, i.e.
+/// an endpoint's virtual nodes, are spread evenly over the ring.
+///
+/// Unlike `DefaultHasher`, and unlike the standard library's `Hash` implementations, it
+/// is stable across Rust releases, so that clients are not remapped by an upgrade.
+struct StableHash(u64);
+
+impl StableHash {
+    fn new() -> StableHash {
+        StableHash(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        for i in (0..8).rev() {
+            self.write(&[(n >> (i * 8)) as u8]);
+        }
+    }
+
+    fn write_ip(&mut self, ip: &net::IpAddr) {
+        match *ip {
+            net::IpAddr::V4(ref ip) => self.write(&ip.octets()),
+            net::IpAddr::V6(ref ip) => self.write(&ip.octets()),
+        }
+    }
+
+    fn write_addr(&mut self, addr: &net::SocketAddr) {
+        self.write_ip(&addr.ip());
+        self.write(&[(addr.port() >> 8) as u8, addr.port() as u8]);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut k = self.0;
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^= k >> 33;
+        k
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StableHash, peak_ewma_cost};
+    use std::net;
+
+    const MS: f64 = 1_000_000.0;
+
+    #[test]
+    fn cold_endpoint_costs_default_latency() {
+        assert_eq!(peak_ewma_cost(0.0, 10.0 * MS, 0), 10.0 * MS);
+    }
+
+    #[test]
+    fn cold_endpoint_cost_grows_with_pending_connections() {
+        let idle = peak_ewma_cost(0.0, 10.0 * MS, 0);
+        let pending = peak_ewma_cost(0.0, 10.0 * MS, 3);
+        assert!(pending > idle);
+        // A warm endpoint with a lower estimate is preferred to a cold endpoint that
+        // already has connections pending.
+        assert!(peak_ewma_cost(12.0 * MS, 10.0 * MS, 1) < pending);
+    }
+
+    #[test]
+    fn slow_endpoint_costs_its_estimate() {
+        assert_eq!(peak_ewma_cost(50.0 * MS, 10.0 * MS, 1), 100.0 * MS);
+    }
+
+    #[test]
+    fn stable_hash_is_fnv1a() {
+        let mut h = StableHash::new();
+        h.write(b"a");
+        assert_eq!(h.0, 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn stable_hash_of_source_ip_does_not_change() {
+        let mut h = StableHash::new();
+        h.write_ip(&net::IpAddr::V4(net::Ipv4Addr::new(10, 1, 2, 3)));
+        assert_eq!(h.finish(), 0xb8b3_87fa_0a1c_d4e9);
+    }
+}
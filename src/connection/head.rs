@@ -0,0 +1,162 @@
+//! Reads the head of an HTTP/1.x message from a stream, as used by HTTP CONNECT and
+//! HTTP health checks.
+
+use futures::{Async, Future, Poll};
+use std::{io, mem};
+use std::io::Read;
+
+/// Limits how much is read from the stream at once.
+const READ_BYTES: usize = 1024;
+
+/// Reads a message head, up to and including the empty line that terminates it.
+///
+/// If the head exceeds `max_bytes`, the partial head is returned; callers should check
+/// that it ends with an empty line.
+pub fn read_head<T: Read>(io: T, max_bytes: usize) -> ReadHead<T> {
+    ReadHead {
+        io: Some(io),
+        buf: Vec::with_capacity(READ_BYTES),
+        max_bytes,
+    }
+}
+
+/// Reads a message head.
+///
+/// The stream is read in chunks, so bytes sent after the head may be read along with
+/// it. These belong to the tunneled stream and are returned after the head so that they
+/// may be passed on.
+pub struct ReadHead<T> {
+    io: Option<T>,
+    buf: Vec<u8>,
+    max_bytes: usize,
+}
+
+impl<T: Read> Future for ReadHead<T> {
+    type Item = (T, Vec<u8>, Vec<u8>);
+    type Error = io::Error;
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            let mut chunk = [0u8; READ_BYTES];
+            let sz = {
+                let io = self.io.as_mut().expect(
+                    "message head must not be polled after completion",
+                );
+                try_nb!(io.read(&mut chunk))
+            };
+            if sz == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before message head completed",
+                ));
+            }
+            // The terminator may span the previous chunk and this one.
+            let from = self.buf.len().saturating_sub(3);
+            self.buf.extend_from_slice(&chunk[..sz]);
+            let end = match find_end(&self.buf[from..]) {
+                Some(end) => Some(from + end),
+                None if self.buf.len() >= self.max_bytes => Some(self.max_bytes),
+                None => None,
+            };
+            if let Some(end) = end {
+                let io = self.io.take().unwrap();
+                let mut head = mem::replace(&mut self.buf, Vec::new());
+                let rest = head.split_off(end.min(self.max_bytes));
+                return Ok(Async::Ready((io, head, rest)));
+            }
+        }
+    }
+}
+
+/// Finds the end of the empty line that terminates a message head.
+fn find_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// Reads the status code from a response head.
+pub fn parse_status(head: &[u8]) -> Option<u16> {
+    if !head.ends_with(b"\r\n\r\n") {
+        return None;
+    }
+    let head = ::std::str::from_utf8(head).ok()?;
+    let mut parts = head.lines().next()?.split(' ');
+    match parts.next() {
+        Some(v) if v.starts_with("HTTP/1.") => {}
+        _ => return None,
+    }
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, read_head};
+    use futures::Future;
+    use std::collections::VecDeque;
+    use std::io::{self, Read};
+
+    /// Returns each chunk from a separate read.
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                None => Ok(0),
+                Some(chunk) => {
+                    assert!(chunk.len() <= buf.len());
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+            }
+        }
+    }
+
+    fn read(chunks: &[&'static [u8]], max_bytes: usize) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let io = Chunks(chunks.iter().cloned().collect());
+        read_head(io, max_bytes).wait().map(|(_, head, rest)| (head, rest))
+    }
+
+    #[test]
+    fn head_in_one_read() {
+        let (head, rest) = read(&[b"HTTP/1.1 200 OK\r\n\r\n"], 1024).unwrap();
+        assert_eq!(head, b"HTTP/1.1 200 OK\r\n\r\n".to_vec());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn head_split_across_reads() {
+        let chunks: &[&'static [u8]] = &[b"HTTP/1.1 200 OK\r", b"\nA: b\r\n\r", b"\n"];
+        let (head, rest) = read(chunks, 1024).unwrap();
+        assert_eq!(head, b"HTTP/1.1 200 OK\r\nA: b\r\n\r\n".to_vec());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn bytes_after_head_are_returned() {
+        let chunks: &[&'static [u8]] = &[b"HTTP/1.1 200 OK\r\n", b"\r\nhello", b"world"];
+        let (head, rest) = read(chunks, 1024).unwrap();
+        assert_eq!(head, b"HTTP/1.1 200 OK\r\n\r\n".to_vec());
+        assert_eq!(rest, b"hello".to_vec());
+    }
+
+    #[test]
+    fn oversized_head_is_truncated() {
+        let (head, rest) = read(&[b"HTTP/1.1 200 OK\r\nA: b\r\n\r\n"], 8).unwrap();
+        assert_eq!(head, b"HTTP/1.1".to_vec());
+        assert_eq!(rest, b" 200 OK\r\nA: b\r\n\r\n".to_vec());
+        assert_eq!(parse_status(&head), None);
+    }
+
+    #[test]
+    fn eof_before_head_completes() {
+        let e = read(&[b"HTTP/1.1 200 OK\r\n"], 1024).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn status() {
+        assert_eq!(parse_status(b"HTTP/1.1 200 Connection established\r\n\r\n"), Some(200));
+        assert_eq!(parse_status(b"HTTP/1.0 407 Auth\r\nA: b\r\n\r\n"), Some(407));
+        assert_eq!(parse_status(b"HTTP/1.1 200 OK\r\n"), None);
+        assert_eq!(parse_status(b"HTTP/2 200\r\n\r\n"), None);
+        assert_eq!(parse_status(b"HTTP/1.1 OK\r\n\r\n"), None);
+    }
+}
@@ -5,6 +5,7 @@ use std::rc::Rc;
 pub mod ctx;
 mod duplex;
 mod half_duplex;
+pub mod head;
 pub mod secure;
 pub mod socket;
 
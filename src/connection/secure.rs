@@ -253,6 +253,26 @@ impl Future for ServerHandshake {
     }
 }
 
+impl SecureStream<ClientSession> {
+    /// Indicates whether the handshake resumed a previous session.
+    ///
+    /// rustls 0.10 does not expose whether a session was resumed. Endpoints do not
+    /// present certificates in abbreviated handshakes, and the pinned rustls revision
+    /// does not restore them from the session cache, so a session without peer
+    /// certificates must have been resumed. Later rustls releases retain the server's
+    /// certificates across resumption, so this must be revisited when upgrading.
+    pub fn is_resumed(&self) -> bool {
+        self.session.get_peer_certificates().map_or(true, |c| c.is_empty())
+    }
+}
+
+impl SecureStream<ServerSession> {
+    /// The server name that the client requested via SNI, if any.
+    pub fn sni_hostname(&self) -> Option<&str> {
+        self.session.get_sni_hostname()
+    }
+}
+
 /// A future that completes when a client's TLS handshake is complete.
 #[derive(Debug)]
 pub struct ClientHandshake(Option<SecureStream<ClientSession>>);
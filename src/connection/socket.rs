@@ -1,17 +1,18 @@
 use super::secure::SecureStream;
 use futures::Poll;
 use rustls::{ClientSession, ServerSession};
-use std::fmt;
+use std::{cmp, fmt};
 use std::io::{self, Read, Write};
 use std::net::{Shutdown, SocketAddr};
 use tokio_core::net::TcpStream;
-use tokio_io::AsyncWrite;
+use tokio_io::{AsyncRead, AsyncWrite};
 
 pub fn plain(tcp: TcpStream) -> Socket {
     Socket {
         local_addr: tcp.local_addr().expect("tcp stream has no local address"),
         peer_addr: tcp.peer_addr().expect("tcp stream has no peer address"),
         kind: Kind::Plain(tcp),
+        unread: Vec::new(),
     }
 }
 
@@ -20,6 +21,7 @@ pub fn secure_client(tls: SecureStream<ClientSession>) -> Socket {
         local_addr: tls.local_addr(),
         peer_addr: tls.peer_addr(),
         kind: Kind::SecureClient(Box::new(tls)),
+        unread: Vec::new(),
     }
 }
 
@@ -28,6 +30,7 @@ pub fn secure_server(tls: SecureStream<ServerSession>) -> Socket {
         local_addr: tls.local_addr(),
         peer_addr: tls.peer_addr(),
         kind: Kind::SecureServer(Box::new(tls)),
+        unread: Vec::new(),
     }
 }
 
@@ -40,6 +43,9 @@ pub struct Socket {
     local_addr: SocketAddr,
     peer_addr: SocketAddr,
     kind: Kind,
+    /// Bytes read ahead of the stream, e.g. after a proxy request's head, that are
+    /// returned before anything more is read from the socket.
+    unread: Vec<u8>,
 }
 
 // Since the rustls types are much larger than the plain type, they are boxed. Because
@@ -92,12 +98,33 @@ impl Socket {
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
+
+    /// Returns bytes that were read ahead of the stream to subsequent reads.
+    pub fn unread(&mut self, mut buf: Vec<u8>) {
+        buf.extend_from_slice(&self.unread);
+        self.unread = buf;
+    }
+
+    /// The server name that a TLS client requested via SNI, if any.
+    pub fn sni_hostname(&self) -> Option<&str> {
+        match self.kind {
+            Kind::SecureServer(ref stream) => stream.sni_hostname(),
+            Kind::Plain(_) |
+            Kind::SecureClient(_) => None,
+        }
+    }
 }
 
 /// Reads the socket without blocking.
 impl Read for Socket {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         trace!("{:?}.read({})", self, buf.len());
+        if !self.unread.is_empty() {
+            let sz = cmp::min(buf.len(), self.unread.len());
+            buf[..sz].copy_from_slice(&self.unread[..sz]);
+            self.unread.drain(..sz);
+            return Ok(sz);
+        }
         match self.kind {
             Kind::Plain(ref mut stream) => stream.read(buf),
             Kind::SecureClient(ref mut stream) => stream.read(buf),
@@ -106,6 +133,8 @@ impl Read for Socket {
     }
 }
 
+impl AsyncRead for Socket {}
+
 /// Writes to the socket without blocking.
 impl Write for Socket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
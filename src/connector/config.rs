@@ -1,6 +1,14 @@
-use super::{Connector, ConnectorFactory, Tls};
+use super::{Connector, ConnectorFactory, FailFast, Hedge, RetryBudget, SlowStart, SuccessRate,
+            Tls};
+use super::health::{HealthCheck, Probe};
+use super::dynamic::DynamicConnectorFactory;
+use super::identity::ClientIdentity;
+use super::prefix::{self, Captures, PathPrefix};
+use super::proxy::{self, Proxy};
+use super::source::Source;
+use super::verify::Verifier;
 use rustls;
-use std::fs::File;
+use std::{env, fs, net};
 use std::io::BufReader;
 use std::sync::Arc;
 use std::time;
@@ -8,6 +16,33 @@ use std::time;
 const DEFAULT_MAX_WAITERS: usize = 1_000_000;
 const DEFAULT_MAX_CONSECUTIVE_FAILURES: usize = 5;
 const DEFAULT_FAILURE_PENALTY_SECS: u64 = 60;
+const DEFAULT_MAX_FAILURE_PENALTY_SECS: u64 = 600;
+const DEFAULT_MAX_EJECTION_PERCENT: usize = 50;
+const DEFAULT_SUCCESS_RATE_MIN_ATTEMPTS: usize = 10;
+const DEFAULT_HEALTH_CHECK_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_HEALTHY_THRESHOLD: usize = 2;
+const DEFAULT_UNHEALTHY_THRESHOLD: usize = 3;
+const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+const DEFAULT_PEAK_EWMA_DECAY_MS: u64 = 10_000;
+const DEFAULT_PEAK_EWMA_LATENCY_MS: u64 = 10;
+const DEFAULT_THROUGHPUT_DECAY_MS: u64 = 5_000;
+const DEFAULT_VIRTUAL_NODES: usize = 100;
+const DEFAULT_SLOW_START_MIN_WEIGHT_PERCENT: u32 = 10;
+const DEFAULT_RETRY_BUDGET_TTL_SECS: u64 = 10;
+const DEFAULT_MIN_RETRIES_PER_SEC: u32 = 10;
+const DEFAULT_PERCENT_CAN_RETRY: u32 = 20;
+const DEFAULT_HEDGE_MAX_PERCENT: u32 = 10;
+
+/// Locations of the operating system's trust bundle on common platforms, in the order
+/// in which they are tried. `SSL_CERT_FILE` takes precedence over all of these.
+const SYSTEM_ROOTS: &'static [&'static str] = &[
+    "/etc/ssl/certs/ca-certificates.crt", // Debian, Ubuntu, Alpine
+    "/etc/pki/tls/certs/ca-bundle.crt", // Fedora, RHEL, CentOS
+    "/etc/ssl/ca-bundle.pem", // OpenSUSE
+    "/etc/ssl/cert.pem", // macOS, OpenBSD
+    "/usr/local/share/certs/ca-root-nss.crt", // FreeBSD
+];
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -15,9 +50,29 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 pub enum Error {
     GlobalWithPrefix,
     StaticWithoutPrefix,
+    InvalidPrefix(String),
+    NotDynamic,
+    Watch(String),
+    ProxyCredentials,
+    SourcePorts(u16, u16),
+    SlowStartMinWeight(u32),
+    MaxEjectionPercent(usize),
+    MinSuccessPercent(u32),
+    HealthCheckInterval,
+    HealthCheckThreshold,
+    HealthCheckWithoutTls,
+    RetryBudgetTtl,
+    HedgeMaxPercent(u32),
+    ClientCertificate(String),
+    ClientPrivateKey(String),
+    EcdsaClientKey(String),
+    TrustCerts(String),
+    SystemRoots,
+    SpkiPin(String),
+    AllowedSan(String),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, tag = "kind")]
 pub enum ConnectorFactoryConfig {
     #[serde(rename = "io.l5d.global")]
@@ -25,6 +80,14 @@ pub enum ConnectorFactoryConfig {
 
     #[serde(rename = "io.l5d.static")]
     Static { configs: Vec<ConnectorConfig> },
+
+    /// Like `io.l5d.static`, but the configurations may be replaced at runtime via the
+    /// admin API or, if `watch` is set, by updating that file.
+    #[serde(rename = "io.l5d.dynamic")]
+    Dynamic {
+        configs: Option<Vec<ConnectorConfig>>,
+        watch: Option<String>,
+    },
 }
 
 impl Default for ConnectorFactoryConfig {
@@ -41,46 +104,489 @@ impl ConnectorFactoryConfig {
                     return Err(Error::GlobalWithPrefix);
                 }
                 let conn = cfg.mk_connector()?;
-                Ok(ConnectorFactory::new_global(conn))
+                Ok(ConnectorFactory::new_global(cfg.clone(), conn))
             }
             ConnectorFactoryConfig::Static { ref configs } => {
-                let mut pfx_configs = Vec::with_capacity(configs.len());
-                for cfg in configs {
-                    match cfg.prefix {
-                        None => {
-                            return Err(Error::StaticWithoutPrefix);
-                        }
-                        Some(ref pfx) => {
-                            pfx_configs.push((pfx.clone().into(), cfg.clone()));
-                        }
-                    }
-                }
-                Ok(ConnectorFactory::new_prefixed(pfx_configs))
+                Ok(ConnectorFactory::new_prefixed(prefixed(configs)?))
+            }
+            ConnectorFactoryConfig::Dynamic {
+                ref configs,
+                ref watch,
+            } => {
+                let configs = configs.as_ref().map(|c| c.as_slice()).unwrap_or(&[]);
+                let f = DynamicConnectorFactory::new(configs, watch.clone())?;
+                Ok(ConnectorFactory::new_dynamic(f))
+            }
+        }
+    }
+}
+
+/// Parses the prefix of each configuration.
+pub fn prefixed(configs: &[ConnectorConfig]) -> Result<Vec<(PathPrefix, ConnectorConfig)>> {
+    let mut pfx_configs = Vec::with_capacity(configs.len());
+    for cfg in configs {
+        match cfg.prefix {
+            None => {
+                return Err(Error::StaticWithoutPrefix);
+            }
+            Some(ref pfx) => {
+                pfx_configs.push((PathPrefix::parse(pfx)?, cfg.clone()));
             }
         }
     }
+    Ok(pfx_configs)
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct ConnectorConfig {
     pub prefix: Option<String>,
+    pub proxy: Option<ProxyConfig>,
     pub tls: Option<TlsConnectorFactoryConfig>,
     pub connect_timeout_ms: Option<u64>,
+    pub tcp_connect_timeout_ms: Option<u64>,
+    pub tls_handshake_timeout_ms: Option<u64>,
+
+    /// The local address from which endpoints are dialed.
+    pub source_address: Option<net::IpAddr>,
+    /// Local ports from which endpoints are dialed, allocated in rotation.
+    pub source_ports: Option<SourcePortsConfig>,
 
     pub max_waiters: Option<usize>,
     pub min_connections: Option<usize>,
 
     pub fail_fast: Option<FailFastConfig>,
 
-    // TODO requeue_budget: Option<RequeueBudget>
+    /// Warms up endpoints after they become available for new connections.
+    pub slow_start: Option<SlowStartConfig>,
+
+    /// Probes endpoints periodically, so that unhealthy endpoints are not used for new
+    /// connections.
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// Selects the endpoint to which each connection is established.
+    pub load_balancer: Option<LoadBalancerConfig>,
+
+    /// Limits retries of failed connection attempts on other endpoints.
+    pub retry_budget: Option<RetryBudgetConfig>,
+
+    /// Races a connection attempt to another endpoint against attempts that are slow.
+    pub hedge: Option<HedgeConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SourcePortsConfig {
+    pub min: u16,
+    pub max: u16,
+}
+
+/// An egress proxy through which endpoints are dialed. TLS, if configured, is
+/// negotiated with the endpoint through the proxy's tunnel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum ProxyConfig {
+    #[serde(rename = "io.l5d.httpConnect")]
+    HttpConnect(ProxyServerConfig),
+
+    #[serde(rename = "io.l5d.socks5")]
+    Socks5(ProxyServerConfig),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProxyServerConfig {
+    pub addr: net::SocketAddr,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ProxyConfig {
+    fn mk_proxy(&self) -> Result<Proxy> {
+        let (kind, config) = match *self {
+            ProxyConfig::HttpConnect(ref c) => (proxy::Kind::HttpConnect, c),
+            ProxyConfig::Socks5(ref c) => (proxy::Kind::Socks5, c),
+        };
+        let credentials = match (&config.username, &config.password) {
+            (&None, &None) => None,
+            (&Some(ref u), &Some(ref p)) => {
+                // SOCKS5 encodes each credential's length in a single byte.
+                if u.len() > 255 || p.len() > 255 {
+                    return Err(Error::ProxyCredentials);
+                }
+                Some((u.clone(), p.clone()))
+            }
+            _ => return Err(Error::ProxyCredentials),
+        };
+        Ok(Proxy::new(kind, config.addr, credentials))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SlowStartConfig {
+    /// The time over which an endpoint's weight ramps up to its resolved weight.
+    pub window_secs: u64,
+    /// The percentage of its resolved weight that an endpoint starts with. Defaults to 10.
+    pub min_weight_percent: Option<u32>,
+}
+
+impl SlowStartConfig {
+    fn mk_slow_start(&self) -> Result<SlowStart> {
+        let pct = self.min_weight_percent.unwrap_or(
+            DEFAULT_SLOW_START_MIN_WEIGHT_PERCENT,
+        );
+        if pct > 100 {
+            return Err(Error::SlowStartMinWeight(pct));
+        }
+        Ok(SlowStart {
+            window: time::Duration::from_secs(self.window_secs),
+            min_weight: f64::from(pct) / 100.0,
+        })
+    }
+}
+
+/// Limits retries relative to the connections requested, like Finagle's retry budget.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct RetryBudgetConfig {
+    /// The time for which requested connections count toward the budget. Defaults to 10.
+    pub ttl_secs: Option<u64>,
+    /// Retries allowed each second, regardless of the connections requested. Defaults
+    /// to 10.
+    pub min_retries_per_sec: Option<u32>,
+    /// Retries allowed per 100 requested connections. Defaults to 20.
+    pub percent_can_retry: Option<u32>,
+}
+
+impl RetryBudgetConfig {
+    fn mk_retry_budget(&self) -> Result<RetryBudget> {
+        let ttl = self.ttl_secs.unwrap_or(DEFAULT_RETRY_BUDGET_TTL_SECS);
+        if ttl == 0 {
+            return Err(Error::RetryBudgetTtl);
+        }
+        let pct = self.percent_can_retry.unwrap_or(DEFAULT_PERCENT_CAN_RETRY);
+        Ok(RetryBudget {
+            ttl: time::Duration::from_secs(ttl),
+            min_retries_per_sec: self.min_retries_per_sec.unwrap_or(
+                DEFAULT_MIN_RETRIES_PER_SEC,
+            ),
+            percent_can_retry: f64::from(pct) / 100.0,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HedgeConfig {
+    /// The time after which a pending attempt is hedged, i.e. the p95 connect latency.
+    pub delay_ms: u64,
+    /// Hedges allowed per 100 requested connections. Defaults to 10.
+    pub max_percent: Option<u32>,
+}
+
+impl HedgeConfig {
+    fn mk_hedge(&self) -> Result<Hedge> {
+        let pct = self.max_percent.unwrap_or(DEFAULT_HEDGE_MAX_PERCENT);
+        if pct > 100 {
+            return Err(Error::HedgeMaxPercent(pct));
+        }
+        Ok(Hedge {
+            delay: time::Duration::from_millis(self.delay_ms),
+            budget: RetryBudget {
+                ttl: time::Duration::from_secs(DEFAULT_RETRY_BUDGET_TTL_SECS),
+                min_retries_per_sec: 0,
+                percent_can_retry: f64::from(pct) / 100.0,
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HealthCheckConfig {
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    /// The number of consecutive successful probes after which an unhealthy endpoint is
+    /// used again. Defaults to 2.
+    pub healthy_threshold: Option<usize>,
+    /// The number of consecutive failed probes after which an endpoint is no longer
+    /// used. Defaults to 3.
+    pub unhealthy_threshold: Option<usize>,
+    pub probe: ProbeConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum ProbeConfig {
+    /// Establishes a TCP connection, through the proxy if one is configured.
+    #[serde(rename = "tcp")]
+    Tcp,
+
+    /// Completes a TLS handshake, as configured by `tls`.
+    #[serde(rename = "tls")]
+    Tls,
+
+    /// Writes `send` over a TCP connection and expects a reply beginning with `expect`.
+    #[serde(rename = "sendExpect")]
+    SendExpect(SendExpectProbeConfig),
+
+    /// Expects a 2xx response to an HTTP GET of `path` on another port of the endpoint's
+    /// host, i.e. an admin port.
+    #[serde(rename = "http")]
+    Http(HttpProbeConfig),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SendExpectProbeConfig {
+    pub send: String,
+    pub expect: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HttpProbeConfig {
+    pub port: u16,
+    /// Defaults to `/`.
+    pub path: Option<String>,
+}
+
+impl HealthCheckConfig {
+    fn mk_health_check(&self, tls: bool) -> Result<HealthCheck> {
+        let interval = self.interval_ms.unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL_MS);
+        if interval == 0 {
+            return Err(Error::HealthCheckInterval);
+        }
+        let healthy_threshold = self.healthy_threshold.unwrap_or(DEFAULT_HEALTHY_THRESHOLD);
+        let unhealthy_threshold = self.unhealthy_threshold.unwrap_or(
+            DEFAULT_UNHEALTHY_THRESHOLD,
+        );
+        if healthy_threshold == 0 || unhealthy_threshold == 0 {
+            return Err(Error::HealthCheckThreshold);
+        }
+        let probe = match self.probe {
+            ProbeConfig::Tcp => Probe::Tcp,
+            ProbeConfig::Tls => {
+                if !tls {
+                    return Err(Error::HealthCheckWithoutTls);
+                }
+                Probe::Tls
+            }
+            ProbeConfig::SendExpect(ref c) => {
+                Probe::SendExpect(c.send.clone().into_bytes(), c.expect.clone().into_bytes())
+            }
+            ProbeConfig::Http(ref c) => {
+                let path = c.path.clone().unwrap_or_else(|| "/".to_owned());
+                Probe::Http(c.port, path)
+            }
+        };
+        Ok(HealthCheck {
+            interval: time::Duration::from_millis(interval),
+            timeout: time::Duration::from_millis(
+                self.timeout_ms.unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_MS),
+            ),
+            healthy_threshold,
+            unhealthy_threshold,
+            probe,
+        })
+    }
+}
+
+/// Load balancing strategies, as named by linkerd.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum LoadBalancerConfig {
+    /// Chooses the lesser-loaded of two random endpoints.
+    #[serde(rename = "p2c")]
+    P2c(P2cConfig),
+
+    /// Chooses each endpoint in turn, regardless of load or weight.
+    #[serde(rename = "roundRobin")]
+    RoundRobin,
+
+    /// Chooses endpoints randomly, in proportion to their weights.
+    #[serde(rename = "weightedRandom")]
+    WeightedRandom,
+
+    /// Chooses the least-loaded of all endpoints.
+    #[serde(rename = "leastConnections")]
+    LeastConnections,
+
+    /// Chooses endpoints by consistently hashing a property of each inbound
+    /// connection, so that each client keeps using the same endpoint.
+    #[serde(rename = "ringHash")]
+    RingHash(RingHashConfig),
+}
+
+impl Default for LoadBalancerConfig {
+    fn default() -> LoadBalancerConfig {
+        LoadBalancerConfig::P2c(P2cConfig::default())
+    }
+}
+
+impl LoadBalancerConfig {
+    /// Controls how quickly endpoints' connect latency estimates forget older
+    /// connections.
+    pub fn latency_decay(&self) -> time::Duration {
+        let ms = match *self {
+            LoadBalancerConfig::P2c(P2cConfig {
+                load: Some(LoadConfig::PeakEwma(ref c)),
+            }) => c.decay_time_ms,
+            _ => None,
+        };
+        time::Duration::from_millis(ms.unwrap_or(DEFAULT_PEAK_EWMA_DECAY_MS))
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct RingHashConfig {
+    /// The property of inbound connections that is hashed. Defaults to `sourceIp`.
+    pub key: Option<HashKey>,
+    /// The number of points at which an endpoint of average weight is placed on the
+    /// ring.
+    pub virtual_nodes: Option<usize>,
+}
+
+impl RingHashConfig {
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes.unwrap_or(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HashKey {
+    /// The IP address of the inbound connection's peer.
+    SourceIp,
+    /// The server name requested by the inbound connection, falling back to its source
+    /// IP if it did not request one.
+    Sni,
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct P2cConfig {
+    pub load: Option<LoadConfig>,
+}
+
+/// Estimates the load of each endpoint.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum LoadConfig {
+    /// The number of open and pending connections.
+    #[serde(rename = "connections")]
+    Connections,
+
+    /// The peak-sensitive moving average of connect latencies, multiplied by the
+    /// number of open and pending connections.
+    #[serde(rename = "peakEwma")]
+    PeakEwma(PeakEwmaConfig),
+
+    /// The moving average rate at which bytes are transferred, optionally mixed with
+    /// the number of open and pending connections.
+    #[serde(rename = "throughput")]
+    Throughput(ThroughputConfig),
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct PeakEwmaConfig {
+    /// The time over which older latencies lose influence.
+    pub decay_time_ms: Option<u64>,
+    /// The latency assumed for endpoints whose estimate is lower, i.e. because they
+    /// have not yet connected, so that their pending connections still count against
+    /// them. Defaults to 10.
+    pub default_latency_ms: Option<u64>,
+}
+
+impl PeakEwmaConfig {
+    pub fn default_latency(&self) -> time::Duration {
+        let ms = self.default_latency_ms.unwrap_or(DEFAULT_PEAK_EWMA_LATENCY_MS);
+        time::Duration::from_millis(ms)
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ThroughputConfig {
+    /// The time over which older transfers lose influence.
+    pub decay_time_ms: Option<u64>,
+    /// The load, in bytes per second, attributed to each open or pending connection.
+    pub bytes_per_connection: Option<u64>,
+}
+
+impl ThroughputConfig {
+    pub fn decay(&self) -> time::Duration {
+        let ms = self.decay_time_ms.unwrap_or(DEFAULT_THROUGHPUT_DECAY_MS);
+        time::Duration::from_millis(ms)
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct FailFastConfig {
     pub max_consecutive_failures: Option<usize>,
     pub failure_penalty_secs: Option<u64>,
+    /// Limits the penalty, which doubles each time an endpoint is ejected again soon
+    /// after it recovers.
+    pub max_failure_penalty_secs: Option<u64>,
+    /// Limits the percentage of endpoints that may be ejected at once. Defaults to 50.
+    /// The limit is rounded up, so that one of two endpoints may be ejected, but the last
+    /// available endpoint is never ejected.
+    pub max_ejection_percent: Option<usize>,
+    pub success_rate: Option<SuccessRateConfig>,
+}
+
+/// Ejects endpoints whose connection attempts over a sliding window succeed too rarely.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SuccessRateConfig {
+    pub window_secs: u64,
+    /// The number of attempts within the window required to judge an endpoint.
+    /// Defaults to 10.
+    pub min_attempts: Option<usize>,
+    pub min_success_percent: u32,
+}
+
+impl FailFastConfig {
+    fn mk_fail_fast(&self) -> Result<FailFast> {
+        let penalty = self.failure_penalty_secs.unwrap_or(
+            DEFAULT_FAILURE_PENALTY_SECS,
+        );
+        let max_penalty = self.max_failure_penalty_secs
+            .unwrap_or(DEFAULT_MAX_FAILURE_PENALTY_SECS)
+            .max(penalty);
+        let max_ejection_percent = self.max_ejection_percent.unwrap_or(
+            DEFAULT_MAX_EJECTION_PERCENT,
+        );
+        if max_ejection_percent > 100 {
+            return Err(Error::MaxEjectionPercent(max_ejection_percent));
+        }
+        let success_rate = match self.success_rate {
+            None => None,
+            Some(ref sr) => {
+                if sr.min_success_percent > 100 {
+                    return Err(Error::MinSuccessPercent(sr.min_success_percent));
+                }
+                Some(SuccessRate {
+                    window: time::Duration::from_secs(sr.window_secs),
+                    min_attempts: sr.min_attempts.unwrap_or(DEFAULT_SUCCESS_RATE_MIN_ATTEMPTS),
+                    min_success_rate: f64::from(sr.min_success_percent) / 100.0,
+                })
+            }
+        };
+        Ok(FailFast {
+            max_consecutive_failures: self.max_consecutive_failures.unwrap_or(
+                DEFAULT_MAX_CONSECUTIVE_FAILURES,
+            ),
+            penalty: time::Duration::from_secs(penalty),
+            max_penalty: time::Duration::from_secs(max_penalty),
+            max_ejection_percent,
+            success_rate,
+        })
+    }
 }
 
 impl ConnectorConfig {
@@ -89,63 +595,260 @@ impl ConnectorConfig {
             None => None,
             Some(ref tls) => Some(tls.mk_tls()?),
         };
+        let ports = match self.source_ports {
+            None => None,
+            Some(SourcePortsConfig { min, max }) => {
+                if min == 0 || max < min {
+                    return Err(Error::SourcePorts(min, max));
+                }
+                Some((min, max))
+            }
+        };
+        let source = Source::new(self.source_address, ports);
+        let proxy = match self.proxy {
+            None => None,
+            Some(ref proxy) => Some(proxy.mk_proxy()?),
+        };
         let connect_timeout = self.connect_timeout_ms.map(time::Duration::from_millis);
+        let tcp_connect_timeout = self.tcp_connect_timeout_ms.map(time::Duration::from_millis);
+        let tls_handshake_timeout = self.tls_handshake_timeout_ms.map(
+            time::Duration::from_millis,
+        );
         let max_waiters = self.max_waiters.unwrap_or(DEFAULT_MAX_WAITERS);
         let min_conns = self.min_connections.unwrap_or(0);
-        let max_fails = self.fail_fast
+        let fail_fast = self.fail_fast
             .as_ref()
-            .and_then(|c| c.max_consecutive_failures)
-            .unwrap_or(DEFAULT_MAX_CONSECUTIVE_FAILURES);
-        let fail_penalty = {
-            let s = self.fail_fast
-                .as_ref()
-                .and_then(|c| c.failure_penalty_secs)
-                .unwrap_or(DEFAULT_FAILURE_PENALTY_SECS);
-            time::Duration::from_secs(s)
+            .map(|c| c.mk_fail_fast())
+            .unwrap_or_else(|| FailFastConfig::default().mk_fail_fast())?;
+        let slow_start = match self.slow_start {
+            None => None,
+            Some(ref ss) => Some(ss.mk_slow_start()?),
+        };
+        let health_check = match self.health_check {
+            None => None,
+            Some(ref hc) => Some(hc.mk_health_check(tls.is_some())?),
+        };
+        let load_balancer = self.load_balancer.clone().unwrap_or_default();
+        let retry_budget = self.retry_budget
+            .as_ref()
+            .map(|c| c.mk_retry_budget())
+            .unwrap_or_else(|| RetryBudgetConfig::default().mk_retry_budget())?;
+        let hedge = match self.hedge {
+            None => None,
+            Some(ref h) => Some(h.mk_hedge()?),
         };
         Ok(super::new(
             connect_timeout,
+            tcp_connect_timeout,
+            tls_handshake_timeout,
+            source,
+            proxy,
             tls,
             max_waiters,
             min_conns,
-            max_fails,
-            fail_penalty,
+            fail_fast,
+            slow_start,
+            health_check,
+            load_balancer,
+            retry_budget,
+            hedge,
         ))
     }
 
+    /// Overrides each field that is set in `other`.
     pub fn update(&mut self, other: &ConnectorConfig) {
         if let Some(ref otls) = other.tls {
-            self.tls = Some(otls.clone());
+            match self.tls {
+                Some(ref mut tls) => tls.update(otls),
+                None => self.tls = Some(otls.clone()),
+            }
+        }
+        update(&mut self.proxy, &other.proxy);
+        update(&mut self.connect_timeout_ms, &other.connect_timeout_ms);
+        update(&mut self.tcp_connect_timeout_ms, &other.tcp_connect_timeout_ms);
+        update(&mut self.tls_handshake_timeout_ms, &other.tls_handshake_timeout_ms);
+        update(&mut self.source_address, &other.source_address);
+        update(&mut self.source_ports, &other.source_ports);
+        update(&mut self.max_waiters, &other.max_waiters);
+        update(&mut self.min_connections, &other.min_connections);
+        if let Some(ref off) = other.fail_fast {
+            let ff = self.fail_fast.get_or_insert_with(FailFastConfig::default);
+            update(&mut ff.max_consecutive_failures, &off.max_consecutive_failures);
+            update(&mut ff.failure_penalty_secs, &off.failure_penalty_secs);
+            update(&mut ff.max_failure_penalty_secs, &off.max_failure_penalty_secs);
+            update(&mut ff.max_ejection_percent, &off.max_ejection_percent);
+            update(&mut ff.success_rate, &off.success_rate);
         }
-        if let Some(ct) = other.connect_timeout_ms {
-            self.connect_timeout_ms = Some(ct);
+        update(&mut self.slow_start, &other.slow_start);
+        update(&mut self.health_check, &other.health_check);
+        update(&mut self.load_balancer, &other.load_balancer);
+        update(&mut self.retry_budget, &other.retry_budget);
+        update(&mut self.hedge, &other.hedge);
+    }
+
+    /// Substitutes segments captured by this configuration's prefix into its values.
+    pub fn substitute(&self, captures: &Captures) -> ConnectorConfig {
+        let mut config = self.clone();
+        if let Some(ref mut tls) = config.tls {
+            tls.substitute(captures);
         }
+        config
     }
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsConnectorFactoryConfig {
     pub dns_name: String,
+    /// Names each endpoint individually from its service discovery metadata, i.e.
+    /// `{authority}` or `{nodeName}.svc`. Falls back to `dns_name`.
+    pub endpoint_dns_name: Option<String>,
+    /// Sends the server name requested by inbound TLS clients to endpoints, and verifies
+    /// endpoints against it, instead of `dns_name`.
+    pub forward_sni: Option<bool>,
     pub trust_certs: Option<Vec<String>>,
+    /// Trusts the operating system's trust bundle in addition to `trust_certs`.
+    pub system_roots: Option<bool>,
+    /// Base64-encoded SHA-256 digests of endpoint public keys (SubjectPublicKeyInfo),
+    /// optionally prefixed with `sha256/`. Endpoints must present one of these keys.
+    pub spki_pins: Option<Vec<String>>,
+    /// Accepts certificates valid for any of these names, i.e. `*.example.com`, instead
+    /// of the name the endpoint was dialed with.
+    pub allowed_sans: Option<Vec<String>>,
+    /// Disables certificate verification entirely. Pins still apply. Only for local
+    /// development.
+    pub insecure_skip_verify: Option<bool>,
+    /// Protocols offered to endpoints via ALPN, in order of preference, i.e. `h2`.
+    pub alpn_protocols: Option<Vec<String>>,
+    /// Bounds the number of sessions cached for resumption. 0 disables resumption.
+    pub session_cache_size: Option<usize>,
+    pub client_identity: Option<TlsClientIdentityConfig>,
+}
+
+/// A certificate chain and private key presented to endpoints that require clients to
+/// authenticate. The files are reloaded when they change.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct TlsClientIdentityConfig {
+    pub certs: Vec<String>,
+    pub private_key: String,
 }
 
 impl TlsConnectorFactoryConfig {
+    /// Overrides each field that is set in `other`.
+    fn update(&mut self, other: &TlsConnectorFactoryConfig) {
+        self.dns_name = other.dns_name.clone();
+        update(&mut self.endpoint_dns_name, &other.endpoint_dns_name);
+        update(&mut self.forward_sni, &other.forward_sni);
+        update(&mut self.trust_certs, &other.trust_certs);
+        update(&mut self.system_roots, &other.system_roots);
+        update(&mut self.spki_pins, &other.spki_pins);
+        update(&mut self.allowed_sans, &other.allowed_sans);
+        update(&mut self.insecure_skip_verify, &other.insecure_skip_verify);
+        update(&mut self.alpn_protocols, &other.alpn_protocols);
+        update(&mut self.session_cache_size, &other.session_cache_size);
+        update(&mut self.client_identity, &other.client_identity);
+    }
+
+    /// Substitutes captured segments into names and file paths.
+    fn substitute(&mut self, captures: &Captures) {
+        let sub = |v: &mut String| *v = prefix::substitute(v, captures);
+        sub(&mut self.dns_name);
+        if let Some(ref mut n) = self.endpoint_dns_name {
+            sub(n);
+        }
+        for v in self.trust_certs.iter_mut().chain(self.allowed_sans.iter_mut()) {
+            for v in v.iter_mut() {
+                sub(v);
+            }
+        }
+        if let Some(ref mut id) = self.client_identity {
+            sub(&mut id.private_key);
+            for c in &mut id.certs {
+                sub(c);
+            }
+        }
+    }
+
     pub fn mk_tls(&self) -> Result<Tls> {
         let mut config = rustls::ClientConfig::new();
         if let Some(ref certs) = self.trust_certs {
             for p in certs {
-                let f = File::open(p).expect("cannot open certificate file");
-                config
-                    .root_store
-                    .add_pem_file(&mut BufReader::new(f))
-                    .expect("certificate error");
+                add_trust_certs(&mut config.root_store, p)?;
+            }
+        };
+        if self.system_roots.unwrap_or(false) {
+            add_system_roots(&mut config.root_store)?;
+        }
+        let insecure = self.insecure_skip_verify.unwrap_or(false);
+        if insecure {
+            warn!(
+                "!!! TLS certificate verification is DISABLED for {}; endpoints are not \
+                 authenticated. Never use insecureSkipVerify in production. !!!",
+                self.dns_name
+            );
+        }
+        if insecure || self.spki_pins.is_some() || self.allowed_sans.is_some() {
+            let verifier = Verifier::new(
+                self.spki_pins.as_ref().map(|p| p.as_slice()),
+                self.allowed_sans.as_ref().map(|s| s.as_slice()),
+                insecure,
+            )?;
+            config.dangerous().set_certificate_verifier(Arc::new(verifier));
+        }
+        if let Some(ref protocols) = self.alpn_protocols {
+            config.set_protocols(protocols);
+        }
+        let cache_size = self.session_cache_size.unwrap_or(DEFAULT_SESSION_CACHE_SIZE);
+        if cache_size > 0 {
+            config.set_persistence(rustls::ClientSessionMemoryCache::new(cache_size));
+        }
+        let identity = match self.client_identity {
+            None => None,
+            Some(ref id) => {
+                let id = Arc::new(ClientIdentity::load(&id.certs, &id.private_key)?);
+                config.client_auth_cert_resolver = id.clone();
+                Some(id)
             }
         };
         let tls = Tls {
             name: self.dns_name.clone(),
+            endpoint_name: self.endpoint_dns_name.clone(),
+            forward_sni: self.forward_sni.unwrap_or(false),
+            identity,
             config: Arc::new(config),
         };
         Ok(tls)
     }
 }
+
+/// Overrides a configured value if another is set.
+fn update<T: Clone>(value: &mut Option<T>, other: &Option<T>) {
+    if other.is_some() {
+        *value = other.clone();
+    }
+}
+
+fn add_trust_certs(store: &mut rustls::RootCertStore, path: &str) -> Result<()> {
+    let f = fs::File::open(path).map_err(
+        |_| Error::TrustCerts(path.to_owned()),
+    )?;
+    match store.add_pem_file(&mut BufReader::new(f)) {
+        Ok((valid, _)) if valid > 0 => Ok(()),
+        _ => Err(Error::TrustCerts(path.to_owned())),
+    }
+}
+
+/// Adds the first trust bundle found on this system.
+fn add_system_roots(store: &mut rustls::RootCertStore) -> Result<()> {
+    if let Ok(path) = env::var("SSL_CERT_FILE") {
+        return add_trust_certs(store, &path);
+    }
+    for path in SYSTEM_ROOTS {
+        if fs::metadata(path).is_ok() {
+            debug!("loading system trust roots from {}", path);
+            return add_trust_certs(store, path);
+        }
+    }
+    Err(Error::SystemRoots)
+}
@@ -0,0 +1,224 @@
+//! Per-prefix connector configurations that may be replaced at runtime.
+
+use super::{Connector, ConnectorConfig, StaticPrefixConnectorFactory};
+use super::super::Path;
+use super::config::{self, Error, Result};
+use super::prefix::PathPrefix;
+use futures::{Async, Future, Poll, Stream, future, unsync};
+use serde_yaml;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, SystemTime};
+use tokio_timer::Timer;
+
+/// How often a watched configuration file is checked for changes.
+const WATCH_INTERVAL_SECS: u64 = 5;
+
+/// Builds connectors from prefixed configurations, like the static factory, but allows
+/// the configurations to be replaced.
+///
+/// When configurations are replaced, new connectors are sent to the balancers of each
+/// name whose configuration changed, so that new connections use the new settings
+/// without balancers being rebuilt.
+#[derive(Clone)]
+pub struct DynamicConnectorFactory(Rc<RefCell<Inner>>);
+
+struct Inner {
+    configs: StaticPrefixConnectorFactory,
+
+    /// Sends replacement connectors to the balancers for each name.
+    balancers: HashMap<Path, Vec<UpdateSender>>,
+
+    /// A file from which configurations are reloaded when it changes.
+    watch: Option<String>,
+    watch_modified: Option<SystemTime>,
+}
+
+impl DynamicConnectorFactory {
+    /// Builds a factory from `configs` or, if a file is to be watched, from that file.
+    pub fn new(configs: &[ConnectorConfig], watch: Option<String>) -> Result<Self> {
+        let (configs, watch_modified) = match watch {
+            None => (config::prefixed(configs)?, None),
+            Some(ref path) => {
+                let modified = modified(path);
+                (read_configs(path)?, modified)
+            }
+        };
+        let inner = Inner {
+            configs: StaticPrefixConnectorFactory(configs),
+            balancers: HashMap::new(),
+            watch,
+            watch_modified,
+        };
+        Ok(DynamicConnectorFactory(Rc::new(RefCell::new(inner))))
+    }
+
+    pub fn mk_connector(&self, dst_name: &Path) -> Result<(Connector, ConnectorUpdates)> {
+        let mut inner = self.0.borrow_mut();
+        let conn = inner.configs.mk_connector(dst_name)?;
+        // Balancers may be built and dropped for names whose configuration never
+        // changes, so dropped balancers are forgotten as new ones are registered.
+        prune(&mut inner.balancers);
+        let (tx, rx) = unsync::mpsc::unbounded();
+        let live = Rc::new(());
+        let sender = UpdateSender {
+            tx,
+            live: Rc::downgrade(&live),
+        };
+        inner
+            .balancers
+            .entry(dst_name.clone())
+            .or_insert_with(Vec::new)
+            .push(sender);
+        Ok((conn, ConnectorUpdates(Some((rx, live)))))
+    }
+
+    pub fn connector_config(&self, dst_name: &Path) -> ConnectorConfig {
+        self.0.borrow().configs.connector_config(dst_name)
+    }
+
+    /// Replaces all configurations.
+    ///
+    /// If a new connector cannot be built for any balancer, no configuration is
+    /// changed. Returns the number of names whose balancers were updated.
+    pub fn update(&self, configs: Vec<(PathPrefix, ConnectorConfig)>) -> Result<usize> {
+        let mut inner = self.0.borrow_mut();
+        let configs = StaticPrefixConnectorFactory(configs);
+        prune(&mut inner.balancers);
+
+        let mut updates = Vec::new();
+        for dst in inner.balancers.keys() {
+            let config = configs.connector_config(dst);
+            if config != inner.configs.connector_config(dst) {
+                updates.push((dst.clone(), config.mk_connector()?));
+            }
+        }
+        inner.configs = configs;
+
+        let mut updated = 0;
+        for (dst, conn) in updates {
+            let live = {
+                let txs = inner.balancers.get_mut(&dst).expect("balancer must exist");
+                // Forget balancers that have been dropped.
+                txs.retain(|s| s.tx.unbounded_send(conn.clone()).is_ok());
+                !txs.is_empty()
+            };
+            if live {
+                debug!("{}: updated connector", dst);
+                updated += 1;
+            } else {
+                inner.balancers.remove(&dst);
+            }
+        }
+        Ok(updated)
+    }
+
+    /// Takes responsibility for updating the balancers built by another factory.
+    pub fn adopt(&self, other: &DynamicConnectorFactory) {
+        if Rc::ptr_eq(&self.0, &other.0) {
+            return;
+        }
+        let balancers = ::std::mem::replace(&mut other.0.borrow_mut().balancers, HashMap::new());
+        let mut inner = self.0.borrow_mut();
+        for (dst, mut txs) in balancers {
+            inner
+                .balancers
+                .entry(dst)
+                .or_insert_with(Vec::new)
+                .append(&mut txs);
+        }
+    }
+
+    /// Reloads configurations whenever the watched file changes, until this factory is
+    /// dropped.
+    ///
+    /// Invalid configurations are logged and ignored.
+    pub fn watch(&self, timer: &Timer) -> Option<Box<Future<Item = (), Error = ()>>> {
+        if self.0.borrow().watch.is_none() {
+            return None;
+        }
+        let factory = Rc::downgrade(&self.0);
+        let interval = timer.interval(Duration::from_secs(WATCH_INTERVAL_SECS));
+        let f = interval.map_err(|_| {}).for_each(move |_| match factory.upgrade() {
+            None => future::err(()),
+            Some(inner) => {
+                DynamicConnectorFactory(inner).reload_watched();
+                future::ok(())
+            }
+        });
+        Some(Box::new(f))
+    }
+
+    fn reload_watched(&self) {
+        let path = {
+            let mut inner = self.0.borrow_mut();
+            let path = inner.watch.clone().expect("watched factory must have a path");
+            let m = modified(&path);
+            if m == inner.watch_modified {
+                return;
+            }
+            inner.watch_modified = m;
+            path
+        };
+        match read_configs(&path).and_then(|configs| self.update(configs)) {
+            Ok(n) => info!("reloaded client configuration from {}: {} updated", path, n),
+            Err(e) => error!("failed to reload client configuration from {}: {:?}", path, e),
+        }
+    }
+}
+
+/// Sends replacement connectors to a balancer.
+struct UpdateSender {
+    tx: unsync::mpsc::UnboundedSender<Connector>,
+    /// Expires when the balancer's `ConnectorUpdates` is dropped.
+    live: Weak<()>,
+}
+
+/// Forgets the balancers that have been dropped, and the names left without balancers.
+fn prune(balancers: &mut HashMap<Path, Vec<UpdateSender>>) {
+    balancers.retain(|_, txs| {
+        txs.retain(|s| s.live.upgrade().is_some());
+        !txs.is_empty()
+    });
+}
+
+/// Receives replacement connectors for a balancer.
+///
+/// Connectors built by static factories are never replaced.
+pub struct ConnectorUpdates(Option<(unsync::mpsc::UnboundedReceiver<Connector>, Rc<()>)>);
+
+impl ConnectorUpdates {
+    pub fn none() -> ConnectorUpdates {
+        ConnectorUpdates(None)
+    }
+}
+
+impl Stream for ConnectorUpdates {
+    type Item = Connector;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Connector>, ()> {
+        match self.0 {
+            None => Ok(Async::NotReady),
+            Some((ref mut rx, _)) => rx.poll(),
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reads a YAML or JSON list of prefixed configurations.
+fn read_configs(path: &str) -> Result<Vec<(PathPrefix, ConnectorConfig)>> {
+    let mut txt = String::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut txt))
+        .map_err(|e| Error::Watch(format!("{}: {}", path, e)))?;
+    let configs: Vec<ConnectorConfig> = serde_yaml::from_str(&txt).map_err(|e| {
+        Error::Watch(format!("{}: {}", path, e))
+    })?;
+    config::prefixed(&configs)
+}
@@ -0,0 +1,92 @@
+//! Probes that actively check the health of endpoints.
+
+use super::super::connection::head::{parse_status, read_head};
+use futures::Future;
+use std::{io, net, time};
+use tokio_core::net::TcpStream;
+use tokio_io::io::{read_exact, write_all};
+
+/// Limits the size of a health check's response head.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Controls how endpoints are probed and how probe results determine their health.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub interval: time::Duration,
+    /// Bounds the time for each probe to complete.
+    pub timeout: time::Duration,
+    /// The number of consecutive successful probes after which an unhealthy endpoint is
+    /// considered healthy.
+    pub healthy_threshold: usize,
+    /// The number of consecutive failed probes after which a healthy endpoint is
+    /// considered unhealthy.
+    pub unhealthy_threshold: usize,
+    pub probe: Probe,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Probe {
+    /// Succeeds if a TCP connection can be established.
+    Tcp,
+    /// Succeeds if a TLS handshake can be completed.
+    Tls,
+    /// Succeeds if, after the given bytes are written, the endpoint replies with the
+    /// expected bytes.
+    SendExpect(Vec<u8>, Vec<u8>),
+    /// Succeeds if an HTTP GET of the given path on the given port of the endpoint's host
+    /// returns a 2xx status.
+    Http(u16, String),
+}
+
+/// Writes `send` and reads a reply that must begin with `expect`.
+///
+/// The connection is provided with any bytes the endpoint sent that were read ahead of
+/// the stream while tunneling through a proxy.
+pub fn send_expect(
+    tcp: IoFuture<(TcpStream, Vec<u8>)>,
+    send: Vec<u8>,
+    expect: Vec<u8>,
+) -> IoFuture<()> {
+    let f = tcp.and_then(move |(tcp, buf)| write_all(tcp, send).map(move |(tcp, _)| (tcp, buf)))
+        .and_then(move |(tcp, mut reply)| {
+            let buf = vec![0u8; expect.len().saturating_sub(reply.len())];
+            read_exact(tcp, buf).map(move |(_, rest)| {
+                reply.extend_from_slice(&rest);
+                (reply, expect)
+            })
+        })
+        .and_then(|(reply, expect)| if reply.starts_with(&expect) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected health check reply",
+            ))
+        });
+    Box::new(f)
+}
+
+/// Requests `path` from `addr`, which must respond with a 2xx status.
+pub fn http_get(tcp: IoFuture<TcpStream>, addr: net::SocketAddr, path: &str) -> IoFuture<()> {
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        addr
+    );
+    let f = tcp.and_then(move |tcp| write_all(tcp, req.into_bytes()))
+        .and_then(|(tcp, _)| read_head(tcp, MAX_HEAD_BYTES))
+        .and_then(|(_, head, _)| match parse_status(&head) {
+            Some(status) if 200 <= status && status < 300 => Ok(()),
+            Some(status) => {
+                let msg = format!("health check failed with status {}", status);
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid health check response",
+            )),
+        });
+    Box::new(f)
+}
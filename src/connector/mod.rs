@@ -1,101 +1,343 @@
-use super::Path;
+use super::{EndpointMeta, Path};
 use super::connection::secure;
 use super::connection::socket::{self, Socket};
-use futures::{Future, Poll};
-use rustls::ClientConfig as RustlsClientConfig;
-use std::{io, net, time};
+use futures::{Future, Poll, future};
+use rustls::{ClientSession, ClientConfig as RustlsClientConfig};
+use std::{error, fmt, io, net, time};
 use std::sync::Arc;
+use std::time::Instant;
+use tacho;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
 use tokio_timer::Timer;
 
 mod config;
+mod dynamic;
+mod health;
+mod identity;
+mod prefix;
+mod proxy;
+mod source;
+mod verify;
 
-pub use self::config::{ConnectorFactoryConfig, ConnectorConfig, TlsConnectorFactoryConfig,
-                       Error as ConfigError};
+pub use self::config::{ConnectorFactoryConfig, ConnectorConfig, HashKey, LoadBalancerConfig,
+                       LoadConfig, TlsConnectorFactoryConfig, Error as ConfigError};
+pub use self::dynamic::ConnectorUpdates;
+pub use self::health::HealthCheck;
+use self::dynamic::DynamicConnectorFactory;
+use self::identity::ClientIdentity;
+use self::prefix::PathPrefix;
+use self::proxy::Proxy;
+use self::source::Source;
 
 /// Builds a connector for each name.
 pub struct ConnectorFactory(ConnectorFactoryInner);
 
 enum ConnectorFactoryInner {
     /// Uses a single connector for all names.
-    StaticGlobal(Connector),
+    StaticGlobal(ConnectorConfig, Connector),
     /// Builds a new connector for each name by applying all configurations with a
-    /// matching prefix. This is considered "static" because the set of configurations may
-    /// not be updated dynamically.
+    /// matching prefix, in order. This is considered "static" because the set of
+    /// configurations may not be updated dynamically.
     StaticPrefixed(StaticPrefixConnectorFactory),
+    /// Builds connectors like `StaticPrefixed`, but its configurations may be replaced at
+    /// runtime, updating the connectors of existing balancers.
+    DynamicPrefixed(DynamicConnectorFactory),
 }
 
 impl ConnectorFactory {
-    pub fn new_global(conn: Connector) -> ConnectorFactory {
-        ConnectorFactory(ConnectorFactoryInner::StaticGlobal(conn))
+    pub fn new_global(config: ConnectorConfig, conn: Connector) -> ConnectorFactory {
+        ConnectorFactory(ConnectorFactoryInner::StaticGlobal(config, conn))
     }
 
-    pub fn new_prefixed(prefixed_configs: Vec<(Path, ConnectorConfig)>) -> ConnectorFactory {
+    pub fn new_prefixed(
+        prefixed_configs: Vec<(PathPrefix, ConnectorConfig)>,
+    ) -> ConnectorFactory {
         let f = StaticPrefixConnectorFactory(prefixed_configs);
         ConnectorFactory(ConnectorFactoryInner::StaticPrefixed(f))
     }
 
-    pub fn mk_connector(&self, dst_name: &Path) -> config::Result<Connector> {
+    fn new_dynamic(f: DynamicConnectorFactory) -> ConnectorFactory {
+        ConnectorFactory(ConnectorFactoryInner::DynamicPrefixed(f))
+    }
+
+    /// Builds a connector for the given name, along with a stream of connectors that
+    /// replace it when its configuration changes.
+    pub fn mk_connector(&self, dst_name: &Path) -> config::Result<(Connector, ConnectorUpdates)> {
+        match self.0 {
+            ConnectorFactoryInner::StaticGlobal(_, ref c) => {
+                Ok((c.clone(), ConnectorUpdates::none()))
+            }
+            ConnectorFactoryInner::StaticPrefixed(ref f) => {
+                let c = f.mk_connector(dst_name)?;
+                Ok((c, ConnectorUpdates::none()))
+            }
+            ConnectorFactoryInner::DynamicPrefixed(ref f) => f.mk_connector(dst_name),
+        }
+    }
+
+    /// Replaces the configurations of a dynamic factory.
+    ///
+    /// Returns the number of names whose connectors were updated.
+    pub fn update_configs(&self, configs: &[ConnectorConfig]) -> config::Result<usize> {
+        match self.0 {
+            ConnectorFactoryInner::DynamicPrefixed(ref f) => f.update(config::prefixed(configs)?),
+            _ => Err(ConfigError::NotDynamic),
+        }
+    }
+
+    /// Takes responsibility for updating the connectors that `other` has built.
+    ///
+    /// Returns false if this factory is dynamic but `other`'s connectors cannot be
+    /// updated, in which case balancers built by `other` should be rebuilt.
+    pub fn adopt(&self, other: &ConnectorFactory) -> bool {
+        match (&self.0, &other.0) {
+            (&ConnectorFactoryInner::DynamicPrefixed(ref f),
+             &ConnectorFactoryInner::DynamicPrefixed(ref o)) => {
+                f.adopt(o);
+                true
+            }
+            (&ConnectorFactoryInner::DynamicPrefixed(_), _) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns a task that reloads a dynamic factory's configurations from a watched
+    /// file, if one is configured.
+    pub fn watch(&self, timer: &Timer) -> Option<Box<Future<Item = (), Error = ()>>> {
+        match self.0 {
+            ConnectorFactoryInner::DynamicPrefixed(ref f) => f.watch(timer),
+            _ => None,
+        }
+    }
+
+    /// Returns the configuration used to build connectors for the given name.
+    pub fn connector_config(&self, dst_name: &Path) -> ConnectorConfig {
         match self.0 {
-            ConnectorFactoryInner::StaticGlobal(ref c) => Ok(c.clone()),
-            ConnectorFactoryInner::StaticPrefixed(ref f) => f.mk_connector(dst_name),
+            ConnectorFactoryInner::StaticGlobal(ref c, _) => c.clone(),
+            ConnectorFactoryInner::StaticPrefixed(ref f) => f.connector_config(dst_name),
+            ConnectorFactoryInner::DynamicPrefixed(ref f) => f.connector_config(dst_name),
         }
     }
 }
 
-struct StaticPrefixConnectorFactory(Vec<(Path, ConnectorConfig)>);
+struct StaticPrefixConnectorFactory(Vec<(PathPrefix, ConnectorConfig)>);
 impl StaticPrefixConnectorFactory {
     /// Builds a new connector by applying all configurations with a matching prefix.
     fn mk_connector(&self, dst_name: &Path) -> config::Result<Connector> {
+        self.connector_config(dst_name).mk_connector()
+    }
+
+    /// Merges all configurations whose prefix matches the name, so that later
+    /// configurations override earlier ones field by field. Segments captured by each
+    /// prefix are substituted into its configuration before it is merged.
+    fn connector_config(&self, dst_name: &Path) -> ConnectorConfig {
         let mut config = ConnectorConfig::default();
         for &(ref pfx, ref c) in &self.0 {
-            if pfx.starts_with(dst_name) {
-                config.update(c);
+            if let Some(captures) = pfx.matches(dst_name) {
+                trace!("{} matches {}", dst_name, pfx);
+                config.update(&c.substitute(&captures));
             }
         }
-        config.mk_connector()
+        config
+    }
+}
+
+/// Records outbound TLS handshakes.
+#[derive(Clone)]
+pub struct TlsMetrics {
+    handshake_latency: tacho::Timer,
+    resumed: tacho::Counter,
+    full: tacho::Counter,
+}
+
+impl TlsMetrics {
+    pub fn new(metrics: &tacho::Scope) -> TlsMetrics {
+        TlsMetrics {
+            handshake_latency: metrics.timer_us("handshake_us"),
+            resumed: metrics.clone().labeled("session", "resumed").counter("handshakes"),
+            full: metrics.clone().labeled("session", "full").counter("handshakes"),
+        }
+    }
+
+    fn record(&self, t0: Instant, tls: &secure::SecureStream<ClientSession>) {
+        self.handshake_latency.record_since(t0);
+        if tls.is_resumed() {
+            self.resumed.incr(1);
+        } else {
+            self.full.incr(1);
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Tls {
     name: String,
+    /// Names each endpoint from its service discovery metadata, substituting
+    /// `{authority}` and `{nodeName}`.
+    endpoint_name: Option<String>,
+    /// If set, the SNI requested by an inbound TLS connection is sent to, and verified
+    /// against, the endpoint.
+    forward_sni: bool,
+    identity: Option<Arc<ClientIdentity>>,
     config: Arc<RustlsClientConfig>,
 }
 
 impl Tls {
-    fn handshake(&self, tcp: TcpStream) -> secure::ClientHandshake {
-        secure::client_handshake(tcp, &self.config, &self.name)
+    fn handshake(&self, tcp: TcpStream, name: &str) -> secure::ClientHandshake {
+        secure::client_handshake(tcp, &self.config, name)
+    }
+
+    /// Determines the name that an endpoint is expected to present.
+    ///
+    /// If SNI is forwarded, the inbound connection's SNI is used. Otherwise, if the
+    /// endpoint's metadata lacks a field used by the endpoint name template, the
+    /// destination's name is used.
+    fn server_name(&self, meta: &EndpointMeta, sni: Option<&str>) -> String {
+        if let (true, Some(sni)) = (self.forward_sni, sni) {
+            return sni.to_owned();
+        }
+        let template = match self.endpoint_name {
+            None => return self.name.clone(),
+            Some(ref t) => t,
+        };
+        let mut name = template.clone();
+        if name.contains("{authority}") {
+            match meta.authority {
+                None => return self.name.clone(),
+                Some(ref a) => name = name.replace("{authority}", strip_port(a)),
+            }
+        }
+        if name.contains("{nodeName}") {
+            match meta.node_name {
+                None => return self.name.clone(),
+                Some(ref n) => name = name.replace("{nodeName}", n),
+            }
+        }
+        name
+    }
+}
+
+/// Removes the port, if any, from an authority.
+fn strip_port(authority: &str) -> &str {
+    match authority.rfind(':') {
+        Some(i) if !authority.ends_with(']') &&
+                   authority[i + 1..].chars().all(|c| c.is_digit(10)) => &authority[..i],
+        _ => authority,
     }
 }
 
 fn new(
     connect_timeout: Option<time::Duration>,
+    tcp_connect_timeout: Option<time::Duration>,
+    tls_handshake_timeout: Option<time::Duration>,
+    source: Source,
+    proxy: Option<Proxy>,
     tls: Option<Tls>,
     max_waiters: usize,
     min_connections: usize,
-    fail_limit: usize,
-    fail_penalty: time::Duration,
+    fail_fast: FailFast,
+    slow_start: Option<SlowStart>,
+    health_check: Option<HealthCheck>,
+    load_balancer: LoadBalancerConfig,
+    retry_budget: RetryBudget,
+    hedge: Option<Hedge>,
 ) -> Connector {
     Connector {
         connect_timeout,
+        tcp_connect_timeout,
+        tls_handshake_timeout,
+        source,
+        proxy,
         tls,
         max_waiters,
         min_connections,
-        fail_limit,
-        fail_penalty,
+        fail_fast,
+        slow_start,
+        health_check,
+        load_balancer,
+        retry_budget,
+        hedge,
     }
 }
 
 #[derive(Clone)]
 pub struct Connector {
+    /// Bounds the time to establish a connection, including any TLS handshake.
     connect_timeout: Option<time::Duration>,
+    /// Bounds the time to establish a TCP connection, including any proxy tunnel.
+    tcp_connect_timeout: Option<time::Duration>,
+    /// Bounds the time to complete a TLS handshake once connected.
+    tls_handshake_timeout: Option<time::Duration>,
+    /// Binds outbound connections to a local address.
+    source: Source,
+    /// If set, endpoints are dialed through an egress proxy.
+    proxy: Option<Proxy>,
     tls: Option<Tls>,
     max_waiters: usize,
     min_connections: usize,
-    fail_limit: usize,
-    fail_penalty: time::Duration,
+    fail_fast: FailFast,
+    slow_start: Option<SlowStart>,
+    health_check: Option<HealthCheck>,
+    load_balancer: LoadBalancerConfig,
+    retry_budget: RetryBudget,
+    hedge: Option<Hedge>,
+}
+
+/// Controls when endpoints are ejected from load balancing, and for how long.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FailFast {
+    /// Ejects endpoints that fail this many consecutive connection attempts.
+    pub max_consecutive_failures: usize,
+    /// The time for which an endpoint is first ejected. Each consecutive ejection doubles
+    /// the time, up to `max_penalty`.
+    pub penalty: time::Duration,
+    pub max_penalty: time::Duration,
+    /// Limits the percentage of endpoints that may be ejected at once. The limit is
+    /// rounded up, but at least one endpoint is always left available.
+    pub max_ejection_percent: usize,
+    /// Ejects endpoints whose connection attempts succeed too rarely.
+    pub success_rate: Option<SuccessRate>,
+}
+
+/// Ejects endpoints whose recent connection attempts succeed too rarely.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuccessRate {
+    /// The time over which attempts are considered.
+    pub window: time::Duration,
+    /// The number of attempts within the window required to judge an endpoint.
+    pub min_attempts: usize,
+    /// The fraction of attempts, in [0, 1], that must succeed.
+    pub min_success_rate: f64,
+}
+
+/// Controls how endpoints are warmed up after becoming available for new connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlowStart {
+    /// The time over which an endpoint's weight ramps up to its resolved weight.
+    pub window: time::Duration,
+    /// The fraction of its resolved weight that an endpoint starts with.
+    pub min_weight: f64,
+}
+
+/// Limits retries of failed connection attempts, relative to the connections requested.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryBudget {
+    /// The time for which requested connections count toward the budget.
+    pub ttl: time::Duration,
+    /// Retries allowed each second, regardless of the connections requested.
+    pub min_retries_per_sec: u32,
+    /// The retries earned by each requested connection, i.e. 0.2.
+    pub percent_can_retry: f64,
+}
+
+/// Controls when a slow connection attempt is hedged by an attempt to another endpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hedge {
+    /// The time after which a pending attempt is hedged.
+    pub delay: time::Duration,
+    /// Limits hedges relative to the connections requested.
+    pub budget: RetryBudget,
 }
 
 impl Connector {
@@ -107,39 +349,234 @@ impl Connector {
         self.min_connections
     }
 
-    pub fn failure_limit(&self) -> usize {
-        self.fail_limit
+    pub fn fail_fast(&self) -> &FailFast {
+        &self.fail_fast
     }
-    pub fn failure_penalty(&self) -> time::Duration {
-        self.fail_penalty
+
+    pub fn slow_start(&self) -> Option<SlowStart> {
+        self.slow_start
     }
 
-    fn timeout<F>(&self, fut: F, timer: &Timer) -> Box<Future<Item = F::Item, Error = io::Error>>
-    where
-        F: Future<Error = io::Error> + 'static,
-    {
-        match self.connect_timeout {
-            None => Box::new(fut),
-            Some(t) => Box::new(timer.timeout(fut, t).map_err(|e| e.into())),
-        }
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn load_balancer(&self) -> &LoadBalancerConfig {
+        &self.load_balancer
+    }
+
+    pub fn retry_budget(&self) -> RetryBudget {
+        self.retry_budget
+    }
+
+    pub fn hedge(&self) -> Option<Hedge> {
+        self.hedge
+    }
+
+    /// Returns a task that reloads the client identity presented to endpoints when its
+    /// files change, unless none is configured or it is already watched.
+    pub fn watch_identity(&self, timer: &Timer) -> Option<Box<Future<Item = (), Error = ()>>> {
+        let tls = self.tls.as_ref()?;
+        ClientIdentity::watch(tls.identity.as_ref()?, timer)
+    }
+
+    /// Indicates whether connections must be established for each inbound connection
+    /// that requested a server name, so that its SNI may be forwarded.
+    pub fn forwards_sni(&self) -> bool {
+        self.tls.as_ref().map(|tls| tls.forward_sni).unwrap_or(false)
+    }
+
+    /// Establishes a TCP connection to an endpoint, through the proxy if one is
+    /// configured.
+    ///
+    /// The connection is returned with any bytes the endpoint sent that were read ahead
+    /// of the stream while establishing a tunnel.
+    fn connect_tcp(
+        &self,
+        addr: &net::SocketAddr,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Box<Future<Item = (TcpStream, Vec<u8>), Error = io::Error>> {
+        let tcp: Box<Future<Item = (TcpStream, Vec<u8>), Error = io::Error>> = match self.proxy {
+            None => Box::new(self.source.connect(addr, reactor).map(|tcp| (tcp, Vec::new()))),
+            Some(ref proxy) => {
+                let dst = *addr;
+                let proxy = proxy.clone();
+                let f = self.source.connect(proxy.addr(), reactor).and_then(
+                    move |tcp| proxy.tunnel(tcp, dst),
+                );
+                Box::new(f)
+            }
+        };
+        let tcp = timeout(tcp, self.tcp_connect_timeout, timer).map_err(|e| {
+            if e.kind() == io::ErrorKind::TimedOut {
+                ConnectError::TcpTimeout.into()
+            } else {
+                e
+            }
+        });
+        Box::new(tcp)
+    }
+
+    /// Probes an endpoint's health, if health checks are configured.
+    pub fn probe(
+        &self,
+        addr: &net::SocketAddr,
+        meta: &EndpointMeta,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Option<Box<Future<Item = (), Error = io::Error>>> {
+        let hc = self.health_check.as_ref()?;
+        let probe: Box<Future<Item = (), Error = io::Error>> = match hc.probe {
+            health::Probe::Tcp => Box::new(self.connect_tcp(addr, reactor, timer).map(|_| {})),
+            health::Probe::Tls => {
+                match self.tls.clone() {
+                    None => {
+                        let e = io::Error::new(io::ErrorKind::Other, "TLS is not configured");
+                        Box::new(future::err(e))
+                    }
+                    Some(tls) => {
+                        let name = tls.server_name(meta, None);
+                        let f = self.connect_tcp(addr, reactor, timer)
+                            .and_then(before_handshake)
+                            .and_then(move |tcp| tls.handshake(tcp, &name).map(|_| {}));
+                        Box::new(f)
+                    }
+                }
+            }
+            health::Probe::SendExpect(ref send, ref expect) => {
+                let f = self.connect_tcp(addr, reactor, timer);
+                health::send_expect(f, send.clone(), expect.clone())
+            }
+            health::Probe::Http(port, ref path) => {
+                let addr = net::SocketAddr::new(addr.ip(), port);
+                let tcp = self.connect_tcp(&addr, reactor, timer).map(|(tcp, _)| tcp);
+                health::http_get(Box::new(tcp), addr, path)
+            }
+        };
+        Some(timeout(probe, Some(hc.timeout), timer))
     }
 
-    pub fn connect(&self, addr: &net::SocketAddr, reactor: &Handle, timer: &Timer) -> Connecting {
-        let tcp = TcpStream::connect(addr, reactor);
+    pub fn connect(
+        &self,
+        addr: &net::SocketAddr,
+        meta: &EndpointMeta,
+        sni: Option<&str>,
+        metrics: &TlsMetrics,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> Connecting {
+        let tcp = self.connect_tcp(addr, reactor, timer);
         let socket: Box<Future<Item = Socket, Error = io::Error>> = match self.tls {
             None => {
-                let f = tcp.map(socket::plain);
-                Box::new(self.timeout(f, timer))
+                Box::new(tcp.map(|(tcp, early)| {
+                    let mut socket = socket::plain(tcp);
+                    socket.unread(early);
+                    socket
+                }))
             }
             Some(ref tls) => {
+                let name = tls.server_name(meta, sni);
                 let tls = tls.clone();
-                let f = tcp.and_then(move |tcp| tls.handshake(tcp)).map(
-                    socket::secure_client,
-                );
-                Box::new(self.timeout(f, timer))
+                let metrics = metrics.clone();
+                let handshake_timeout = self.tls_handshake_timeout;
+                let timer = timer.clone();
+                let f = tcp.and_then(before_handshake).and_then(move |tcp| {
+                    let t0 = Instant::now();
+                    let handshake = tls.handshake(tcp, &name);
+                    timeout(handshake, handshake_timeout, &timer)
+                        .map(move |tls| {
+                            metrics.record(t0, &tls);
+                            socket::secure_client(tls)
+                        })
+                        .map_err(|e| if e.kind() == io::ErrorKind::TimedOut {
+                            ConnectError::TlsTimeout.into()
+                        } else {
+                            ConnectError::TlsHandshake(e).into()
+                        })
+                });
+                Box::new(f)
             }
         };
-        Connecting(socket)
+        Connecting(timeout(socket, self.connect_timeout, timer))
+    }
+}
+
+/// Fails if an endpoint sent data before a TLS handshake, since TLS servers never speak
+/// first.
+fn before_handshake((tcp, early): (TcpStream, Vec<u8>)) -> io::Result<TcpStream> {
+    if early.is_empty() {
+        Ok(tcp)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "endpoint sent data before the TLS handshake",
+        ))
+    }
+}
+
+/// Fails with `TimedOut` if `fut` does not complete within `timeout`.
+fn timeout<F>(
+    fut: F,
+    timeout: Option<time::Duration>,
+    timer: &Timer,
+) -> Box<Future<Item = F::Item, Error = io::Error>>
+where
+    F: Future<Error = io::Error> + 'static,
+{
+    match timeout {
+        None => Box::new(fut),
+        Some(t) => Box::new(timer.timeout(fut, t).map_err(|e| e.into())),
+    }
+}
+
+/// Describes the phase in which an outbound connection failed, so that failures may be
+/// classified. Other failures are described by their `io::ErrorKind` alone.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The TCP connection, including any proxy tunnel, was not established in time.
+    TcpTimeout,
+    /// The TLS handshake did not complete in time.
+    TlsTimeout,
+    /// The TLS handshake failed.
+    TlsHandshake(io::Error),
+}
+
+impl ConnectError {
+    /// Finds the phase in which a connection failed, if it is known.
+    pub fn from_io(e: &io::Error) -> Option<&ConnectError> {
+        e.get_ref().and_then(|e| e.downcast_ref::<ConnectError>())
+    }
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(e: ConnectError) -> io::Error {
+        let kind = match e {
+            ConnectError::TcpTimeout |
+            ConnectError::TlsTimeout => io::ErrorKind::TimedOut,
+            ConnectError::TlsHandshake(ref e) => e.kind(),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectError::TcpTimeout => f.write_str("TCP connect timed out"),
+            ConnectError::TlsTimeout => f.write_str("TLS handshake timed out"),
+            ConnectError::TlsHandshake(ref e) => write!(f, "TLS handshake failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for ConnectError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectError::TcpTimeout => "TCP connect timed out",
+            ConnectError::TlsTimeout => "TLS handshake timed out",
+            ConnectError::TlsHandshake(_) => "TLS handshake failed",
+        }
     }
 }
 
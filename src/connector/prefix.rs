@@ -0,0 +1,143 @@
+//! Matches destination names against configured prefixes.
+
+use super::super::Path;
+use super::config::{Error, Result};
+use std::fmt;
+
+/// Values of the named segments captured by a prefix.
+pub type Captures = Vec<(String, String)>;
+
+/// A path prefix, matched segment by segment.
+///
+/// A `*` segment matches any single segment, and a `{name}` segment matches any single
+/// segment and captures it as `name`. For example, `/svc/{service}` matches
+/// `/svc/users/grpc`, capturing `service` as `users`, but does not match `/svcs/users`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathPrefix {
+    prefix: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Wildcard,
+    Capture(String),
+}
+
+impl PathPrefix {
+    pub fn parse(prefix: &str) -> Result<PathPrefix> {
+        if !prefix.starts_with('/') {
+            return Err(Error::InvalidPrefix(prefix.to_owned()));
+        }
+        let mut segments = Vec::new();
+        for seg in prefix.split('/').filter(|s| !s.is_empty()) {
+            let seg = if seg == "*" {
+                Segment::Wildcard
+            } else if seg.starts_with('{') && seg.ends_with('}') {
+                let name = &seg[1..seg.len() - 1];
+                if name.is_empty() || name.contains(|c| c == '{' || c == '}') {
+                    return Err(Error::InvalidPrefix(prefix.to_owned()));
+                }
+                Segment::Capture(name.to_owned())
+            } else if seg.contains(|c| c == '*' || c == '{' || c == '}') {
+                return Err(Error::InvalidPrefix(prefix.to_owned()));
+            } else {
+                Segment::Literal(seg.to_owned())
+            };
+            segments.push(seg);
+        }
+        Ok(PathPrefix {
+            prefix: prefix.to_owned(),
+            segments,
+        })
+    }
+
+    /// If the name starts with this prefix, returns the segments it captured.
+    pub fn matches(&self, name: &Path) -> Option<Captures> {
+        let mut captures = Vec::new();
+        let mut name = name.segments().into_iter();
+        for seg in &self.segments {
+            let n = name.next()?;
+            match *seg {
+                Segment::Literal(ref l) if l == n => {}
+                Segment::Literal(_) => return None,
+                Segment::Wildcard => {}
+                Segment::Capture(ref c) => captures.push((c.clone(), n.to_owned())),
+            }
+        }
+        Some(captures)
+    }
+}
+
+impl fmt::Display for PathPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.prefix)
+    }
+}
+
+/// Substitutes captured segments, i.e. `{service}`, into a configured value.
+pub fn substitute(value: &str, captures: &Captures) -> String {
+    let mut value = value.to_owned();
+    for &(ref name, ref seg) in captures {
+        value = value.replace(&format!("{{{}}}", name), seg);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PathPrefix, substitute};
+    use super::super::super::Path;
+
+    fn matches(prefix: &str, name: &str) -> Option<Vec<(String, String)>> {
+        PathPrefix::parse(prefix).unwrap().matches(&Path::from(name))
+    }
+
+    fn captured(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn literal_matches_whole_segments() {
+        assert_eq!(matches("/svc/foo", "/svc/foo"), Some(vec![]));
+        assert_eq!(matches("/svc/foo", "/svc/foo/bar"), Some(vec![]));
+        assert_eq!(matches("/svc/foo", "/svc/foobar"), None);
+        assert_eq!(matches("/svc/foo", "/svc"), None);
+        assert_eq!(matches("/", "/svc/foo"), Some(vec![]));
+    }
+
+    #[test]
+    fn wildcard_matches_one_segment() {
+        assert_eq!(matches("/svc/*/grpc", "/svc/users/grpc"), Some(vec![]));
+        assert_eq!(matches("/svc/*/grpc", "/svc/users/http"), None);
+        assert_eq!(matches("/svc/*", "/svc"), None);
+    }
+
+    #[test]
+    fn capture_matches_one_segment() {
+        assert_eq!(
+            matches("/svc/{service}/{port}", "/svc/users/8080/grpc"),
+            Some(vec![captured("service", "users"), captured("port", "8080")])
+        );
+        assert_eq!(matches("/svc/{service}", "/svcs/users"), None);
+        assert_eq!(matches("/svc/{service}", "/svc"), None);
+    }
+
+    #[test]
+    fn invalid_prefixes() {
+        for p in &["svc", "/svc/{}", "/svc/foo*", "/svc/{a{b}", "/svc/{name"] {
+            assert!(PathPrefix::parse(p).is_err(), "{} must be invalid", p);
+        }
+    }
+
+    #[test]
+    fn substitute_captures() {
+        let captures = vec![captured("service", "users"), captured("port", "8080")];
+        assert_eq!(
+            substitute("{service}.example.com:{port}", &captures),
+            "users.example.com:8080"
+        );
+        assert_eq!(substitute("{other}.example.com", &captures), "{other}.example.com");
+    }
+}
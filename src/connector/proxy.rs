@@ -0,0 +1,196 @@
+//! Establishes tunnels to endpoints through an HTTP CONNECT or SOCKS5 egress proxy.
+
+use super::super::connection::head::{parse_status, read_head};
+use base64;
+use futures::{Future, future};
+use std::io;
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_io::io::{read_exact, write_all};
+
+/// Limits the size of a proxy's response head.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_AUTH_VERSION: u8 = 0x01;
+const SOCKS_METHOD_NONE: u8 = 0x00;
+const SOCKS_METHOD_PASSWORD: u8 = 0x02;
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// An egress proxy through which endpoints are dialed.
+#[derive(Clone)]
+pub struct Proxy {
+    kind: Kind,
+    addr: SocketAddr,
+    credentials: Option<Credentials>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    HttpConnect,
+    Socks5,
+}
+
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Proxy {
+    pub fn new(kind: Kind, addr: SocketAddr, credentials: Option<(String, String)>) -> Proxy {
+        Proxy {
+            kind,
+            addr,
+            credentials: credentials.map(|(username, password)| {
+                Credentials { username, password }
+            }),
+        }
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Asks the proxy, over an established connection, to open a tunnel to `dst`.
+    ///
+    /// Proxy failures are reported with the error kinds of the equivalent direct
+    /// connection failures, i.e. `ConnectionRefused`, so that they are counted alike.
+    ///
+    /// Any bytes from `dst` that were read along with the proxy's response are returned
+    /// with the tunnel.
+    pub fn tunnel(&self, tcp: TcpStream, dst: SocketAddr) -> IoFuture<(TcpStream, Vec<u8>)> {
+        trace!("connected to proxy {}; tunneling to {}", self.addr, dst);
+        let creds = self.credentials.clone();
+        match self.kind {
+            Kind::HttpConnect => http_connect(tcp, dst, creds),
+            Kind::Socks5 => Box::new(socks5(tcp, dst, creds).map(|tcp| (tcp, Vec::new()))),
+        }
+    }
+}
+
+fn http_connect(
+    tcp: TcpStream,
+    dst: SocketAddr,
+    creds: Option<Credentials>,
+) -> IoFuture<(TcpStream, Vec<u8>)> {
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", dst, dst);
+    if let Some(c) = creds {
+        let token = base64::encode(format!("{}:{}", c.username, c.password).as_bytes());
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    req.push_str("\r\n");
+
+    let f = write_all(tcp, req.into_bytes())
+        .and_then(|(tcp, _)| read_head(tcp, MAX_HEAD_BYTES))
+        .and_then(move |(tcp, head, rest)| -> io::Result<(TcpStream, Vec<u8>)> {
+            let status = parse_status(&head).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid proxy response")
+            })?;
+            if 200 <= status && status < 300 {
+                return Ok((tcp, rest));
+            }
+            let kind = match status {
+                403 | 407 => io::ErrorKind::PermissionDenied,
+                502 | 503 => io::ErrorKind::ConnectionRefused,
+                504 => io::ErrorKind::TimedOut,
+                _ => io::ErrorKind::Other,
+            };
+            let msg = format!("proxy could not connect to {}: status {}", dst, status);
+            Err(io::Error::new(kind, msg))
+        });
+    Box::new(f)
+}
+
+fn socks5(tcp: TcpStream, dst: SocketAddr, creds: Option<Credentials>) -> IoFuture<TcpStream> {
+    let method = if creds.is_some() {
+        SOCKS_METHOD_PASSWORD
+    } else {
+        SOCKS_METHOD_NONE
+    };
+    let f = write_all(tcp, [SOCKS_VERSION, 1, method])
+        .and_then(|(tcp, _)| read_exact(tcp, [0u8; 2]))
+        .and_then(move |(tcp, rsp)| -> IoFuture<TcpStream> {
+            if rsp[0] != SOCKS_VERSION || rsp[1] != method {
+                let e = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "proxy rejected authentication method",
+                );
+                return Box::new(future::err(e));
+            }
+            match creds {
+                None => Box::new(future::ok(tcp)),
+                Some(c) => socks5_authenticate(tcp, c),
+            }
+        })
+        .and_then(move |tcp| {
+            let mut req = vec![SOCKS_VERSION, SOCKS_CMD_CONNECT, 0x00];
+            match dst {
+                SocketAddr::V4(a) => {
+                    req.push(SOCKS_ATYP_IPV4);
+                    req.extend_from_slice(&a.ip().octets());
+                }
+                SocketAddr::V6(a) => {
+                    req.push(SOCKS_ATYP_IPV6);
+                    req.extend_from_slice(&a.ip().octets());
+                }
+            }
+            req.push((dst.port() >> 8) as u8);
+            req.push(dst.port() as u8);
+            write_all(tcp, req)
+        })
+        .and_then(|(tcp, _)| read_exact(tcp, [0u8; 4]))
+        .and_then(move |(tcp, rsp)| -> IoFuture<TcpStream> {
+            if rsp[1] != 0x00 {
+                let kind = match rsp[1] {
+                    0x02 => io::ErrorKind::PermissionDenied,
+                    0x05 => io::ErrorKind::ConnectionRefused,
+                    0x06 => io::ErrorKind::TimedOut,
+                    _ => io::ErrorKind::Other,
+                };
+                let msg = format!("proxy could not connect to {}: reply {}", dst, rsp[1]);
+                return Box::new(future::err(io::Error::new(kind, msg)));
+            }
+            // Discard the address that the proxy bound.
+            let f = match rsp[3] {
+                SOCKS_ATYP_IPV4 => Box::new(read_exact(tcp, vec![0u8; 4 + 2])) as IoFuture<_>,
+                SOCKS_ATYP_IPV6 => Box::new(read_exact(tcp, vec![0u8; 16 + 2])),
+                SOCKS_ATYP_DOMAIN => {
+                    let f = read_exact(tcp, [0u8; 1]).and_then(|(tcp, len)| {
+                        read_exact(tcp, vec![0u8; len[0] as usize + 2])
+                    });
+                    Box::new(f)
+                }
+                atyp => {
+                    let msg = format!("proxy replied with unknown address type: {}", atyp);
+                    return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData, msg)));
+                }
+            };
+            Box::new(f.map(|(tcp, _)| tcp))
+        });
+    Box::new(f)
+}
+
+/// Authenticates with a username and password (RFC 1929).
+fn socks5_authenticate(tcp: TcpStream, creds: Credentials) -> IoFuture<TcpStream> {
+    let mut req = vec![SOCKS_AUTH_VERSION, creds.username.len() as u8];
+    req.extend_from_slice(creds.username.as_bytes());
+    req.push(creds.password.len() as u8);
+    req.extend_from_slice(creds.password.as_bytes());
+    let f = write_all(tcp, req)
+        .and_then(|(tcp, _)| read_exact(tcp, [0u8; 2]))
+        .and_then(|(tcp, rsp)| if rsp[1] == 0x00 {
+            Ok(tcp)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "proxy authentication failed",
+            ))
+        });
+    Box::new(f)
+}
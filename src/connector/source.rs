@@ -0,0 +1,104 @@
+//! Binds outbound connections to a local address and, optionally, a range of ports.
+
+use futures::{Future, future};
+use net2::TcpBuilder;
+use std::cell::Cell;
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Determines the local address of outbound connections.
+///
+/// By default, the kernel chooses both the address and the port.
+#[derive(Clone, Default)]
+pub struct Source {
+    ip: Option<IpAddr>,
+    ports: Option<Ports>,
+}
+
+/// Allocates ports from a range in rotation, so that recently-closed ports are reused as
+/// late as possible.
+#[derive(Clone)]
+struct Ports {
+    min: u16,
+    max: u16,
+    /// Shared by all clones of a connector.
+    next: Rc<Cell<u16>>,
+}
+
+impl Source {
+    pub fn new(ip: Option<IpAddr>, ports: Option<(u16, u16)>) -> Source {
+        Source {
+            ip,
+            ports: ports.map(|(min, max)| {
+                Ports {
+                    min,
+                    max,
+                    next: Rc::new(Cell::new(min)),
+                }
+            }),
+        }
+    }
+
+    pub fn connect(&self, dst: &SocketAddr, reactor: &Handle) -> IoFuture<TcpStream> {
+        if self.ip.is_none() && self.ports.is_none() {
+            return Box::new(TcpStream::connect(dst, reactor));
+        }
+        match self.bind(dst) {
+            Ok(tcp) => Box::new(TcpStream::connect_stream(tcp, dst, reactor)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Binds a socket from which `dst` may be dialed.
+    ///
+    /// If every port in the range is in use, fails with `AddrNotAvailable`, as the kernel
+    /// does when its ephemeral ports are exhausted.
+    fn bind(&self, dst: &SocketAddr) -> io::Result<net::TcpStream> {
+        let ip = self.ip.unwrap_or_else(|| match *dst {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        });
+        let ports = match self.ports {
+            None => return bind(SocketAddr::new(ip, 0)),
+            Some(ref ports) => ports,
+        };
+        for _ in 0..ports.len() {
+            match bind(SocketAddr::new(ip, ports.next())) {
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                res => return res,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "all source ports are in use",
+        ))
+    }
+}
+
+impl Ports {
+    fn len(&self) -> u32 {
+        u32::from(self.max) - u32::from(self.min) + 1
+    }
+
+    fn next(&self) -> u16 {
+        let port = self.next.get();
+        self.next.set(if port >= self.max { self.min } else { port + 1 });
+        port
+    }
+}
+
+/// Binds a socket without `SO_REUSEADDR`, so that ports in use by other sockets,
+/// including those in `TIME_WAIT`, fail with `AddrInUse` and are skipped.
+fn bind(addr: SocketAddr) -> io::Result<net::TcpStream> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.bind(addr)?;
+    builder.to_tcp_stream()
+}
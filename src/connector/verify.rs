@@ -0,0 +1,374 @@
+//! Verifies endpoint certificates with optional public key pinning and server name
+//! allow-lists.
+
+use super::config::{Error, Result};
+use base64;
+use ring::digest;
+use rustls::{Certificate, RootCertStore, ServerCertVerifier, TLSError, WebPKIVerifier};
+
+const PIN_PREFIX: &'static str = "sha256/";
+
+/// Verifies endpoint certificates.
+///
+/// Unless verification is disabled, the certificate chain must be issued by a trusted
+/// root. If an allow-list is configured, the certificate must be valid for one of the
+/// allowed names instead of the name the endpoint was dialed with. If pins are
+/// configured, the endpoint's public key must match one of them, even when
+/// verification is disabled.
+pub struct Verifier {
+    /// SHA-256 digests of acceptable SubjectPublicKeyInfo structures.
+    pins: Option<Vec<Vec<u8>>>,
+    allowed_sans: Option<Vec<SanPattern>>,
+    insecure: bool,
+}
+
+impl Verifier {
+    pub fn new(
+        pins: Option<&[String]>,
+        allowed_sans: Option<&[String]>,
+        insecure: bool,
+    ) -> Result<Verifier> {
+        let pins = match pins {
+            None => None,
+            Some(pins) => Some(pins.iter().map(|p| parse_pin(p)).collect::<Result<_>>()?),
+        };
+        let allowed_sans = match allowed_sans {
+            None => None,
+            Some(sans) => Some(sans.iter().map(|s| SanPattern::new(s)).collect::<Result<_>>()?),
+        };
+        Ok(Verifier {
+            pins,
+            allowed_sans,
+            insecure,
+        })
+    }
+
+    fn verify_chain(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: &str,
+    ) -> ::std::result::Result<(), TLSError> {
+        let webpki = WebPKIVerifier {};
+        match self.allowed_sans {
+            None => webpki.verify_server_cert(roots, presented_certs, dns_name),
+            Some(ref patterns) => {
+                // Wildcard certificate names cannot be verified by name, so only
+                // literal names are considered.
+                let names = dns_sans(&presented_certs[0].0);
+                let allowed = names.iter().find(|n| {
+                    !n.starts_with("*.") && patterns.iter().any(|p| p.matches(n))
+                });
+                match allowed {
+                    Some(name) => webpki.verify_server_cert(roots, presented_certs, name),
+                    None => Err(TLSError::General(
+                        format!("certificate names are not allowed: {:?}", names),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: &str,
+    ) -> ::std::result::Result<(), TLSError> {
+        if presented_certs.is_empty() {
+            return Err(TLSError::NoCertificatesPresented);
+        }
+        if self.insecure {
+            warn!("accepting unverified certificate for {}", dns_name);
+        } else {
+            self.verify_chain(roots, presented_certs, dns_name)?;
+        }
+
+        if let Some(ref pins) = self.pins {
+            let spki = spki(&presented_certs[0].0).ok_or_else(|| {
+                TLSError::General("malformed certificate".into())
+            })?;
+            let d = digest::digest(&digest::SHA256, spki);
+            if !pins.iter().any(|p| p.as_slice() == d.as_ref()) {
+                let pin = format!("{}{}", PIN_PREFIX, base64::encode(d.as_ref()));
+                return Err(TLSError::General(format!("public key is not pinned: {}", pin)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a pin as base64, optionally prefixed with `sha256/`.
+fn parse_pin(pin: &str) -> Result<Vec<u8>> {
+    let encoded = if pin.starts_with(PIN_PREFIX) {
+        &pin[PIN_PREFIX.len()..]
+    } else {
+        pin
+    };
+    match base64::decode(encoded) {
+        Ok(ref d) if d.len() == digest::SHA256.output_len => Ok(d.clone()),
+        _ => Err(Error::SpkiPin(pin.to_owned())),
+    }
+}
+
+/// Matches a DNS name exactly, or, if the pattern starts with `*.`, matches any name
+/// with exactly one label before the rest of the pattern.
+struct SanPattern(String);
+
+impl SanPattern {
+    fn new(pattern: &str) -> Result<SanPattern> {
+        let pattern = pattern.to_lowercase();
+        let name = if pattern.starts_with("*.") {
+            &pattern[2..]
+        } else {
+            &pattern[..]
+        };
+        if name.is_empty() || name.contains('*') {
+            return Err(Error::AllowedSan(pattern.clone()));
+        }
+        Ok(SanPattern(pattern))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        if self.0.starts_with("*.") {
+            let suffix = &self.0[1..];
+            name.len() > suffix.len() && name.ends_with(suffix) &&
+                !name[..name.len() - suffix.len()].contains('.')
+        } else {
+            name == self.0
+        }
+    }
+}
+
+// A minimal DER reader, sufficient to find the public key and subject alternative names
+// of an X.509 certificate. The chain has already been validated by webpki when these are
+// used, except in insecure mode, so malformed input is simply rejected.
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_OID: u8 = 0x06;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const TAG_DNS_NAME: u8 = 0x82;
+
+/// The DER-encoded OID 2.5.29.17 (id-ce-subjectAltName).
+const OID_SUBJECT_ALT_NAME: &'static [u8] = &[0x55, 0x1d, 0x11];
+
+/// A DER element: its tag, its contents, and its complete encoding.
+struct Der<'a> {
+    tag: u8,
+    contents: &'a [u8],
+    encoded: &'a [u8],
+}
+
+/// Reads one DER element, returning it and the remaining input.
+fn read_der(input: &[u8]) -> Option<(Der, &[u8])> {
+    if input.len() < 2 {
+        return None;
+    }
+    let (len, hdr) = match input[1] {
+        n if n < 0x80 => (n as usize, 2),
+        0x81 => (*input.get(2)? as usize, 3),
+        0x82 => {
+            let hi = *input.get(2)? as usize;
+            let lo = *input.get(3)? as usize;
+            ((hi << 8) | lo, 4)
+        }
+        _ => return None,
+    };
+    if input.len() < hdr + len {
+        return None;
+    }
+    let der = Der {
+        tag: input[0],
+        contents: &input[hdr..hdr + len],
+        encoded: &input[..hdr + len],
+    };
+    Some((der, &input[hdr + len..]))
+}
+
+fn read_tagged(input: &[u8], tag: u8) -> Option<(Der, &[u8])> {
+    read_der(input).and_then(|(der, rest)| if der.tag == tag {
+        Some((der, rest))
+    } else {
+        None
+    })
+}
+
+/// Finds the fields of a certificate's TBSCertificate that follow the serial number.
+fn tbs_fields(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = read_tagged(cert, TAG_SEQUENCE)?;
+    let (tbs, _) = read_tagged(cert.contents, TAG_SEQUENCE)?;
+    let mut rest = tbs.contents;
+    if let Some((_, r)) = read_tagged(rest, TAG_VERSION) {
+        rest = r;
+    }
+    // Skip the serial number.
+    read_der(rest).map(|(_, r)| r)
+}
+
+/// Returns the encoded SubjectPublicKeyInfo of a certificate.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let mut rest = tbs_fields(cert)?;
+    // Skip the signature algorithm, issuer, validity and subject.
+    for _ in 0..4 {
+        rest = read_tagged(rest, TAG_SEQUENCE)?.1;
+    }
+    read_tagged(rest, TAG_SEQUENCE).map(|(spki, _)| spki.encoded)
+}
+
+/// Returns the DNS names in a certificate's subject alternative name extension.
+fn dns_sans(cert: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(sans) = subject_alt_names(cert) {
+        let mut rest = sans;
+        while let Some((name, r)) = read_der(rest) {
+            if name.tag == TAG_DNS_NAME {
+                if let Ok(name) = ::std::str::from_utf8(name.contents) {
+                    names.push(name.to_owned());
+                }
+            }
+            rest = r;
+        }
+    }
+    names
+}
+
+/// Returns the contents of the GeneralNames sequence in a certificate's subject
+/// alternative name extension.
+fn subject_alt_names(cert: &[u8]) -> Option<&[u8]> {
+    let mut rest = tbs_fields(cert)?;
+    // Skip the remaining fields until the extensions.
+    let exts = loop {
+        let (der, r) = read_der(rest)?;
+        if der.tag == TAG_EXTENSIONS {
+            break der;
+        }
+        rest = r;
+    };
+    let (exts, _) = read_tagged(exts.contents, TAG_SEQUENCE)?;
+    let mut rest = exts.contents;
+    while let Some((ext, r)) = read_tagged(rest, TAG_SEQUENCE) {
+        rest = r;
+        let (oid, mut value) = read_tagged(ext.contents, TAG_OID)?;
+        if oid.contents != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        if let Some((_, r)) = read_tagged(value, TAG_BOOLEAN) {
+            value = r;
+        }
+        let (value, _) = read_tagged(value, TAG_OCTET_STRING)?;
+        return read_tagged(value.contents, TAG_SEQUENCE).map(|(names, _)| names.contents);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SanPattern, Verifier, dns_sans, parse_pin, read_der, spki};
+    use base64;
+    use ring::digest;
+    use rustls::{Certificate, RootCertStore, ServerCertVerifier};
+
+    /// A self-signed certificate for `a.example.com` with the subject alternative names
+    /// `DNS:a.example.com`, `IP:127.0.0.1` and `DNS:b.example.com`.
+    const CERT: &'static str = "\
+        MIIBtjCCAVygAwIBAgIUUkxy1bfYow5zI+W8wYL4f6kc4z0wCgYIKoZIzj0EAwIw\
+        GDEWMBQGA1UEAwwNYS5leGFtcGxlLmNvbTAeFw0yNjEwMTgxNjQwNTBaFw0zNjEw\
+        MTUxNjQwNTBaMBgxFjAUBgNVBAMMDWEuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIB\
+        BggqhkjOPQMBBwNCAAT5a1+6cCaASsdQX/iL7ZVAsczSKlCJVP5JEuzQkmgg5a31\
+        bz0DxjqlMcGVRW9Vh1JZnS5sF7SOx7FOUFtrZlrCo4GDMIGAMB0GA1UdDgQWBBQ0\
+        AFzhjCTtovP7/xupysPiIG7ljTAfBgNVHSMEGDAWgBQ0AFzhjCTtovP7/xupysPi\
+        IG7ljTAPBgNVHRMBAf8EBTADAQH/MC0GA1UdEQQmMCSCDWEuZXhhbXBsZS5jb22H\
+        BH8AAAGCDWIuZXhhbXBsZS5jb20wCgYIKoZIzj0EAwIDSAAwRQIgKRVEbfSvfGvU\
+        hvATN+RDXF3p6BrikfPrOAa5aDYM05sCIQCaM951N5UcIqfQctJKR8N38j+dpHyD\
+        0iunSUU1Tn12Kg==";
+
+    /// The SHA-256 digest of the certificate's SubjectPublicKeyInfo, as computed by
+    /// `openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst -sha256`.
+    const PIN: &'static str = "sha256/E5Jn67LES9ur2bcGGVtm8V6PUuhgCSucvgOpaghZK90=";
+
+    fn cert() -> Vec<u8> {
+        base64::decode(CERT).expect("test certificate must be base64")
+    }
+
+    fn verify(pins: &[&str]) -> bool {
+        let pins = pins.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let verifier = Verifier::new(Some(&pins[..]), None, true).expect("pins must be valid");
+        let certs = [Certificate(cert())];
+        verifier
+            .verify_server_cert(&RootCertStore::empty(), &certs, "a.example.com")
+            .is_ok()
+    }
+
+    #[test]
+    fn read_der_lengths() {
+        let (der, rest) = read_der(&[0x04, 0x01, 0xff, 0x00]).unwrap();
+        assert_eq!((der.tag, der.contents, rest), (0x04, &[0xff][..], &[0x00][..]));
+
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend_from_slice(&[0u8; 0x80]);
+        assert_eq!(read_der(&long).unwrap().0.contents.len(), 0x80);
+
+        assert!(read_der(&[0x04, 0x02, 0xff]).is_none());
+        assert!(read_der(&[0x04, 0x83, 0x00, 0x00, 0x01, 0x00]).is_none());
+    }
+
+    #[test]
+    fn spki_of_certificate() {
+        let cert = cert();
+        let spki = spki(&cert).expect("certificate must have a public key");
+        let d = digest::digest(&digest::SHA256, spki);
+        assert_eq!(d.as_ref(), parse_pin(PIN).unwrap().as_slice());
+    }
+
+    #[test]
+    fn dns_sans_of_certificate() {
+        assert_eq!(dns_sans(&cert()), vec!["a.example.com", "b.example.com"]);
+    }
+
+    #[test]
+    fn pinned_key_is_accepted() {
+        assert!(verify(&[PIN]));
+        assert!(verify(&["sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=", PIN]));
+    }
+
+    #[test]
+    fn unpinned_key_is_rejected() {
+        assert!(!verify(&["sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]));
+    }
+
+    #[test]
+    fn pins_must_be_sha256_digests() {
+        assert!(parse_pin("E5Jn67LES9ur2bcGGVtm8V6PUuhgCSucvgOpaghZK90=").is_ok());
+        assert!(parse_pin("sha256/AAAA").is_err());
+        assert!(parse_pin("sha256/not base64").is_err());
+    }
+
+    #[test]
+    fn wildcard_matches_one_label() {
+        let p = SanPattern::new("*.example.com").unwrap();
+        assert!(p.matches("a.example.com"));
+        assert!(p.matches("A.Example.COM"));
+        assert!(!p.matches("a.b.example.com"));
+        assert!(!p.matches("example.com"));
+        assert!(!p.matches(".example.com"));
+    }
+
+    #[test]
+    fn literal_matches_exactly() {
+        let p = SanPattern::new("example.com").unwrap();
+        assert!(p.matches("example.com"));
+        assert!(!p.matches("a.example.com"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(SanPattern::new("*.").is_err());
+        assert!(SanPattern::new("a.*.example.com").is_err());
+    }
+}
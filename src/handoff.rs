@@ -0,0 +1,341 @@
+//! Hands listening sockets from a running process to its replacement.
+//!
+//! When a handoff socket is configured, a starting process first tries to connect to it.
+//! If an older process is listening, it sends the listening sockets of all of its servers
+//! over the connection (as `SCM_RIGHTS` ancillary data) and the new process serves on them
+//! instead of binding new ones, so that no connections are refused during an upgrade.
+//!
+//! Once the new process has spawned all of its servers, it acknowledges the handoff and
+//! replaces the old process's control socket. The old process then stops accepting
+//! connections and drains its active streams until its grace deadline.
+
+use super::app::Closer;
+use futures::{Future, Poll, Stream};
+use futures::sync::{mpsc, oneshot};
+use libc;
+use serde_json;
+use std::{cmp, io, mem, net, ptr, thread};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+static REQUEST: &'static [u8] = b"handoff\n";
+static ACK: &'static [u8] = b"ok\n";
+
+/// The maximum number of file descriptors that may be passed in a single message.
+const MAX_FDS: usize = 253;
+
+/// Limits the size of the message describing the sockets being handed off.
+const MAX_MSG_BYTES: usize = 64 * 1024;
+
+/// Bounds the amount of time the old process waits for its replacement.
+const TIMEOUT_SECS: u64 = 30;
+
+/// Listening sockets, by the address on which they were configured to listen.
+pub type Listeners = Vec<(net::SocketAddr, net::TcpListener)>;
+
+/// Asks the serving thread for copies of its current listening sockets.
+pub type Request = oneshot::Sender<Listeners>;
+
+/// Configures the control socket used to hand listening sockets between processes.
+pub struct Handoff {
+    path: PathBuf,
+    grace: Duration,
+}
+
+impl Handoff {
+    /// Configures a hand-off over the Unix socket at `path`.
+    ///
+    /// When this process hands off its listeners, it drains for `grace` before exiting.
+    pub fn new(path: PathBuf, grace: Duration) -> Handoff {
+        Handoff { path, grace }
+    }
+
+    /// Obtains listening sockets from a previous process, if one is running.
+    pub fn inherit(&self) -> io::Result<Inherited> {
+        let mut stream = match UnixStream::connect(&self.path) {
+            Ok(s) => s,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound ||
+                              e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("no process to inherit listeners from at {:?}", self.path);
+                return Ok(Inherited::default());
+            }
+            Err(e) => return Err(e),
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+        info!("inheriting listeners via {:?}", self.path);
+        stream.write_all(REQUEST)?;
+        let (msg, mut sockets) = recv_fds(&mut stream)?;
+        let addrs: Vec<net::SocketAddr> = serde_json::from_slice(&msg).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })?;
+        if addrs.len() != sockets.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "received {} sockets for {} addresses",
+                    sockets.len(),
+                    addrs.len()
+                ),
+            ));
+        }
+
+        let mut listeners = HashMap::with_capacity(addrs.len());
+        for (addr, sock) in addrs.into_iter().zip(sockets.drain(..)) {
+            debug!("inherited listener on {}", addr);
+            listeners.insert(addr, sock);
+        }
+        Ok(Inherited {
+            listeners,
+            peer: Some(stream),
+        })
+    }
+
+    /// Serves handoff requests from a background thread.
+    ///
+    /// Requests for listening sockets are forwarded to the serving thread via the
+    /// returned `Requests`. Once a handoff has been acknowledged by the new process, a
+    /// shutdown deadline is sent on `closer`.
+    pub fn listen(self, closer: Closer) -> io::Result<Requests> {
+        // Either the socket is stale or the process that created it has already handed
+        // off its listeners to this process.
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let listener = UnixListener::bind(&self.path)?;
+        info!("accepting handoffs on {:?}", self.path);
+
+        let (tx, rx) = mpsc::unbounded();
+        let Handoff { path, grace } = self;
+        thread::Builder::new().name("handoff".into()).spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("failed to accept handoff on {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                match serve(stream, &tx) {
+                    Ok(()) => {
+                        info!("listeners handed off; closing");
+                        let _ = closer.unbounded_send(Instant::now() + grace);
+                        return;
+                    }
+                    Err(e) => warn!("handoff failed: {}", e),
+                }
+            }
+        })?;
+        Ok(Requests(rx))
+    }
+}
+
+/// Listening sockets received from a previous process.
+#[derive(Default)]
+pub struct Inherited {
+    listeners: HashMap<net::SocketAddr, net::TcpListener>,
+    peer: Option<UnixStream>,
+}
+
+impl Inherited {
+    /// Claims the listening socket for the given address, if one was inherited.
+    pub fn take(&mut self, addr: &net::SocketAddr) -> Option<net::TcpListener> {
+        self.listeners.remove(addr)
+    }
+
+    /// Tells the previous process that this process is serving on its sockets.
+    ///
+    /// Any sockets that were not claimed are closed.
+    pub fn complete(self) -> io::Result<()> {
+        for addr in self.listeners.keys() {
+            info!("closing unused inherited listener on {}", addr);
+        }
+        match self.peer {
+            None => Ok(()),
+            Some(mut peer) => peer.write_all(ACK),
+        }
+    }
+}
+
+/// A stream of requests from the handoff thread for the serving thread's listening
+/// sockets.
+pub struct Requests(mpsc::UnboundedReceiver<Request>);
+impl Stream for Requests {
+    type Item = Request;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Request>, ()> {
+        self.0.poll()
+    }
+}
+
+/// Hands listening sockets to a new process.
+///
+/// Succeeds only if the new process acknowledges that it is serving.
+fn serve(mut stream: UnixStream, requests: &mpsc::UnboundedSender<Request>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+    let mut req = vec![0u8; REQUEST.len()];
+    stream.read_exact(&mut req)?;
+    if &req[..] != REQUEST {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected handoff request",
+        ));
+    }
+
+    let listeners = {
+        let (tx, rx) = oneshot::channel();
+        requests.unbounded_send(tx).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "serving thread stopped")
+        })?;
+        rx.wait().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "serving thread stopped")
+        })?
+    };
+
+    let addrs = listeners.iter().map(|&(a, _)| a).collect::<Vec<_>>();
+    let fds = listeners
+        .iter()
+        .map(|&(_, ref l)| l.as_raw_fd())
+        .collect::<Vec<_>>();
+    let msg = serde_json::to_vec(&addrs).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+    send_fds(&mut stream, &msg, &fds)?;
+    // The new process has its own copies of the sockets now.
+    drop(listeners);
+
+    let mut ack = vec![0u8; ACK.len()];
+    stream.read_exact(&mut ack)?;
+    if &ack[..] != ACK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected handoff acknowledgement",
+        ));
+    }
+    Ok(())
+}
+
+/// Duplicates a listening socket so that it may be handed to another process.
+pub fn dup_listener(fd: RawFd) -> io::Result<net::TcpListener> {
+    let dup = unsafe { libc::dup(fd) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { net::TcpListener::from_raw_fd(dup) })
+}
+
+/// Writes a length-prefixed message with the given file descriptors attached.
+fn send_fds(stream: &mut UnixStream, msg: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_FDS || msg.len() > MAX_MSG_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many listeners to hand off",
+        ));
+    }
+
+    let mut buf = Vec::with_capacity(4 + msg.len());
+    let len = msg.len();
+    buf.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    buf.extend_from_slice(msg);
+
+    let fds_len = fds.len() * mem::size_of::<RawFd>();
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize];
+    let sent = unsafe {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut hdr: libc::msghdr = mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        if !fds.is_empty() {
+            hdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = cmsg_buf.len() as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(cmsg), fds_len);
+        }
+        libc::sendmsg(stream.as_raw_fd(), &hdr, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The descriptors accompany the first byte, so the rest may be written normally.
+    stream.write_all(&buf[sent as usize..])
+}
+
+/// Reads a length-prefixed message along with any listening sockets attached to it.
+fn recv_fds(stream: &mut UnixStream) -> io::Result<(Vec<u8>, Vec<net::TcpListener>)> {
+    let mut buf = vec![0u8; 4 + MAX_MSG_BYTES];
+    let mut cmsg_buf =
+        vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize];
+    // Received descriptors are owned immediately so that they are closed if the handoff
+    // fails.
+    let mut sockets = Vec::new();
+    let (rcvd, flags) = unsafe {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut hdr: libc::msghdr = mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = cmsg_buf.len() as _;
+        let rcvd = libc::recvmsg(stream.as_raw_fd(), &mut hdr, 0);
+        if rcvd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..(len / mem::size_of::<RawFd>()) {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    sockets.push(net::TcpListener::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+        (rcvd as usize, hdr.msg_flags)
+    };
+    if flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handoff descriptors were truncated",
+        ));
+    }
+
+    let mut len = rcvd;
+    while len < 4 || len < 4 + msg_len(&buf) {
+        let sz = stream.read(&mut buf[len..])?;
+        if sz == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "handoff message was truncated",
+            ));
+        }
+        len += sz;
+    }
+    let msg = buf[4..4 + msg_len(&buf)].to_vec();
+    Ok((msg, sockets))
+}
+
+fn msg_len(buf: &[u8]) -> usize {
+    let len = (buf[0] as usize) << 24 | (buf[1] as usize) << 16 | (buf[2] as usize) << 8 |
+        buf[3] as usize;
+    cmp::min(len, MAX_MSG_BYTES)
+}
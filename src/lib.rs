@@ -7,13 +7,17 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+extern crate base64;
 extern crate bytes;
 #[macro_use]
 extern crate log;
 extern crate futures;
 extern crate hyper;
+extern crate libc;
+extern crate net2;
 extern crate ordermap;
 extern crate rand;
+extern crate ring;
 extern crate rustls;
 extern crate serde;
 #[macro_use]
@@ -24,6 +28,7 @@ extern crate tacho;
 extern crate tokio_core;
 #[macro_use]
 extern crate tokio_io;
+extern crate tokio_signal;
 extern crate tokio_timer;
 extern crate url;
 
@@ -32,10 +37,13 @@ pub mod app;
 mod balancer;
 mod connection;
 mod connector;
+mod handoff;
 mod path;
+mod reload;
 mod resolver;
 mod router;
 mod server;
+mod signals;
 
-use balancer::WeightedAddr;
+use balancer::{EndpointMeta, WeightedAddr};
 use path::Path;
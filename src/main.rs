@@ -9,11 +9,13 @@ extern crate tokio_core;
 extern crate tokio_timer;
 
 use clap::{Arg, App as ClapApp};
-use linkerd_tcp::app::{self, AppConfig, App, AdminRunner, RouterSpawner};
+use futures::Stream;
+use linkerd_tcp::app::{self, AppConfig, App, AdminRunner, Inherited, RouterSpawner, Running};
 use std::collections::VecDeque;
 use std::fs;
 use std::io::Read;
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::{process, thread};
 use tokio_core::reactor::{Core, Handle};
 use tokio_timer::Timer;
 
@@ -39,8 +41,8 @@ fn main() {
         .get_matches();
 
     // Parse configuration file.
+    let path = opts.value_of(CONFIG_PATH_ARG).unwrap();
     let config: AppConfig = {
-        let path = opts.value_of(CONFIG_PATH_ARG).unwrap();
         let mut txt = String::new();
         let res = match path {
             "-" => ::std::io::stdin().read_to_string(&mut txt),
@@ -57,50 +59,119 @@ fn main() {
     // connected by synchronization primitives as needed, but no work is being done yet.
     // Next, we'll attach each of these to a reactor in an independent thread, driving
     // both admin and serving work.
-    let App { routers, admin } = config.into_app().expect("failed to load configuration");
+    let App {
+        routers,
+        mut admin,
+        handoff,
+        running,
+        reloads,
+    } = config.into_app().expect("failed to load configuration");
     debug!("loaded app");
 
+    // Configuration read from stdin cannot be reloaded.
+    if path != "-" {
+        admin.reload_config_from(path);
+    }
+
     let (closer, closed) = app::closer();
 
     // A single timer for the whole process.  The default hashwheel timer has a
     // granularity of 100ms.
     let timer = Timer::default();
 
+    // Schedule all routers on the main thread, serving on the listening sockets of a
+    // previous process if one is handing them off.
+    // The admin server's socket is handed off as well, since the previous process holds
+    // it until it has drained.
+    let mut core = Core::new().expect("failed to initialize server reactor");
+    let admin_listener = {
+        let mut inherited = match handoff.as_ref() {
+            None => Inherited::default(),
+            Some(h) => h.inherit().expect("failed to inherit listeners"),
+        };
+        let admin_listener = admin.bind(&mut inherited).expect(
+            "failed to bind the admin server",
+        );
+        spawn_routers(routers, &core.handle(), &timer, &mut inherited, &running);
+        inherited.complete().expect("failed to complete handoff");
+        admin_listener
+    };
+    if let Some(handoff) = handoff {
+        let requests = handoff.listen(closer.clone()).expect(
+            "failed to listen for handoffs",
+        );
+        core.handle().spawn(running.serve_handoffs(requests, admin_listener));
+    }
+    let reloading = running.serve_reloads(reloads, &core.handle(), &timer);
+    core.handle().spawn(reloading);
+
     // Create a background admin thread that runs an admin server and executes executes
-    // namerd resolutions
-    let admin_thread = spawn_admin(admin, closer, &timer);
-    run_routers(routers, closed, &timer);
-    admin_thread.join().expect("failed to join admin thread");
+    // namerd resolutions. The admin thread is not joined: the process exits once all
+    // servers have closed, or as soon as the admin thread fails.
+    spawn_admin(admin, closer, &timer);
+    run_routers(core, running, closed);
     debug!("stopped")
 }
 
-fn spawn_admin(admin: AdminRunner, closer: app::Closer, timer: &Timer) -> thread::JoinHandle<()> {
+/// Runs the admin server on a background thread.
+///
+/// Name resolution and signal handling also run on the admin thread, so the process
+/// can't serve correctly without it: if it fails, the process exits.
+fn spawn_admin(admin: AdminRunner, closer: app::Closer, timer: &Timer) {
     let timer = timer.clone();
     thread::Builder::new()
         .name("admin".into())
         .spawn(move || {
             debug!("running admin server");
-            let mut core = Core::new().expect("failed to initialize admin reactor");
-            admin.run(closer, &mut core, &timer).expect(
-                "failed to run the admin server",
-            );
+            let res = panic::catch_unwind(AssertUnwindSafe(move || {
+                let mut core = Core::new().expect("failed to initialize admin reactor");
+                admin.run(closer, &mut core, &timer)
+            }));
+            match res {
+                Ok(Ok(())) => error!("admin server stopped; exiting"),
+                Ok(Err(e)) => error!("failed to run the admin server: {:?}; exiting", e),
+                Err(_) => error!("admin thread panicked; exiting"),
+            }
+            process::exit(1);
         })
-        .expect("failed to spawn admin thread")
+        .expect("failed to spawn admin thread");
 }
 
-fn run_routers(routers: VecDeque<RouterSpawner>, closed: app::Closed, timer: &Timer) {
-    // Schedule all routers on the main thread.
-    let mut core = Core::new().expect("failed to initialize server reactor");
-    spawn_routers(routers, &core.handle(), timer);
-
+fn run_routers(mut core: Core, running: Running, closed: app::Closed) {
     // Run until the admin thread closes the application.
     debug!("running until admin server closes");
-    core.run(closed).expect("failed to run");
+    let (deadline, closed) = match core.run(closed.into_future()) {
+        Ok((Some(deadline), closed)) => (deadline, closed),
+        Ok((None, _)) |
+        Err(_) => return,
+    };
+
+    // Stop accepting connections and wait for active streams to complete. Subsequent
+    // deadlines may shorten the grace period.
+    debug!("closing servers");
+    running.close(deadline);
+    let updates = {
+        let running = running.clone();
+        closed.for_each(move |deadline| {
+            running.close(deadline);
+            Ok(())
+        })
+    };
+    core.handle().spawn(updates);
+    core.run(running.closed()).expect("failed to close servers");
 }
 
-fn spawn_routers(mut routers: VecDeque<RouterSpawner>, reactor: &Handle, timer: &Timer) {
+fn spawn_routers(
+    mut routers: VecDeque<RouterSpawner>,
+    reactor: &Handle,
+    timer: &Timer,
+    inherited: &mut Inherited,
+    running: &Running,
+) {
     while let Some(r) = routers.pop_front() {
         debug!("spawning router");
-        r.spawn(reactor, timer).expect("failed to spawn router");
+        r.spawn(reactor, timer, inherited, running).expect(
+            "failed to spawn router",
+        );
     }
 }
@@ -14,16 +14,9 @@ impl Path {
         self.0.len()
     }
 
-    pub fn starts_with(&self, other: &Path) -> bool {
-        let &Path(ref other) = other;
-        if self.0.len() > other.len() {
-            self.0.starts_with(other) &&
-                (self.0.ends_with('/') || other[self.0.len()..].starts_with('/'))
-        } else if other.len() == self.0.len() {
-            self.0 == *other
-        } else {
-            false
-        }
+    /// Iterates over the non-empty segments of the path.
+    pub fn segments(&self) -> Vec<&str> {
+        self.0.split('/').filter(|s| !s.is_empty()).collect()
     }
 }
 impl fmt::Display for Path {
@@ -43,3 +36,15 @@ impl<'a> From<&'a str> for Path {
         Path(s.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Path;
+
+    #[test]
+    fn segments_skip_empty_segments() {
+        assert_eq!(Path::from("/svc/foo").segments(), vec!["svc", "foo"]);
+        assert_eq!(Path::from("//svc//foo/").segments(), vec!["svc", "foo"]);
+        assert!(Path::from("/").segments().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+//! Reloads the process's configuration while it is running.
+//!
+//! Configuration is read and parsed on the admin thread, which sends it to the serving
+//! thread to be applied. The serving thread responds with a summary of the changes it
+//! made, along with the resolver tasks of any new routers so that they may be executed
+//! on the admin thread.
+//!
+//! Dynamic client configurations are replaced over the same channel.
+
+use super::app::AppConfig;
+use super::connector::ConnectorConfig;
+use super::resolver;
+use futures::{Future, Poll, Stream, future};
+use futures::sync::{mpsc, oneshot};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use tacho;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+/// Creates a channel over which configurations are sent to be applied.
+pub fn new(metrics: &tacho::Scope) -> (Reloader, Reloads) {
+    let (tx, rx) = mpsc::unbounded();
+    let reloader = Reloader {
+        path: None,
+        requests: tx,
+        reloads: metrics.counter("reloads"),
+        failures: metrics.counter("reload_failures"),
+    };
+    (reloader, Reloads(rx))
+}
+
+/// Asks the serving thread to change its configuration.
+pub enum Request {
+    /// Applies a new configuration.
+    Reload {
+        /// The configuration to be applied.
+        config: AppConfig,
+
+        /// Receives the result of applying the configuration.
+        rsp: oneshot::Sender<Result<Reloaded, String>>,
+    },
+
+    /// Replaces the configurations of a router's dynamic client.
+    ClientConfig {
+        /// The label of the router to be updated.
+        router: String,
+
+        /// The client configurations to be applied.
+        configs: Vec<ConnectorConfig>,
+
+        /// Receives a summary of the result of applying the configurations.
+        rsp: oneshot::Sender<Result<String, String>>,
+    },
+}
+
+/// Describes a configuration that has been applied.
+pub struct Reloaded {
+    /// Resolves names for routers that were created by the reload.
+    pub resolvers: VecDeque<resolver::Executor>,
+
+    /// A human-readable description of the changes that were applied.
+    pub summary: String,
+}
+
+/// Receives configuration reload requests on the serving thread.
+pub struct Reloads(mpsc::UnboundedReceiver<Request>);
+
+impl Stream for Reloads {
+    type Item = Request;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Request>, ()> {
+        self.0.poll()
+    }
+}
+
+/// Reads the configuration file and sends it to the serving thread.
+#[derive(Clone)]
+pub struct Reloader {
+    path: Option<PathBuf>,
+    requests: mpsc::UnboundedSender<Request>,
+    reloads: tacho::Counter,
+    failures: tacho::Counter,
+}
+
+impl Reloader {
+    /// Sets the path from which configuration is reloaded.
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.path = Some(path);
+    }
+
+    /// Reloads the configuration file.
+    ///
+    /// Resolvers for new routers are spawned on `handle`. If the configuration cannot be
+    /// read, parsed, or applied, the previous configuration continues to run and an
+    /// error describing the failure is returned.
+    pub fn reload(&self, handle: &Handle, timer: &Timer) -> Box<Future<Item = String, Error = String>> {
+        self.reloads.incr(1);
+        let failures = self.failures.clone();
+
+        let config = match self.read_config() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("failed to reload configuration: {}", e);
+                failures.incr(1);
+                return Box::new(future::err(e));
+            }
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let req = Request::Reload {
+            config: config,
+            rsp: tx,
+        };
+        if self.requests.unbounded_send(req).is_err() {
+            failures.incr(1);
+            return Box::new(future::err("server is not running".into()));
+        }
+
+        let handle = handle.clone();
+        let timer = timer.clone();
+        let f = rx.then(move |rsp| match rsp {
+            Err(_) => Err("server did not respond".into()),
+            Ok(rsp) => rsp,
+        }).then(move |rsp| match rsp {
+                Err(e) => {
+                    error!("failed to reload configuration: {}", e);
+                    failures.incr(1);
+                    Err(e)
+                }
+                Ok(Reloaded {
+                       mut resolvers,
+                       summary,
+                   }) => {
+                    info!("reloaded configuration: {}", summary);
+                    while let Some(resolver) = resolvers.pop_front() {
+                        handle.spawn(resolver.execute(&handle, &timer));
+                    }
+                    Ok(summary)
+                }
+            });
+        Box::new(f)
+    }
+
+    /// Replaces the configurations of a router's dynamic client.
+    ///
+    /// Existing balancers use the new configurations for new connections. If the
+    /// configurations cannot be applied, the previous configurations continue to be
+    /// used and an error describing the failure is returned.
+    pub fn update_client_config(
+        &self,
+        router: String,
+        configs: Vec<ConnectorConfig>,
+    ) -> Box<Future<Item = String, Error = String>> {
+        let (tx, rx) = oneshot::channel();
+        let req = Request::ClientConfig {
+            router: router.clone(),
+            configs,
+            rsp: tx,
+        };
+        if self.requests.unbounded_send(req).is_err() {
+            return Box::new(future::err("server is not running".into()));
+        }
+        let f = rx.then(move |rsp| {
+            let rsp = match rsp {
+                Err(_) => Err("server did not respond".into()),
+                Ok(rsp) => rsp,
+            };
+            match rsp {
+                Ok(ref summary) => info!("{}: updated client configuration: {}", router, summary),
+                Err(ref e) => error!("{}: failed to update client configuration: {}", router, e),
+            }
+            rsp
+        });
+        Box::new(f)
+    }
+
+    fn read_config(&self) -> Result<AppConfig, String> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Err("configuration was not loaded from a file".into()),
+        };
+        let mut txt = String::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut txt))
+            .map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+        txt.parse().map_err(|e| format!("error parsing {}: {:?}", path.display(), e))
+    }
+}
@@ -11,7 +11,7 @@ pub enum Error {
     InvalidBaseUrl(String, url::ParseError),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct NamerdConfig {
     pub base_url: String,
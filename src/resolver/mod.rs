@@ -1,4 +1,4 @@
-use super::{WeightedAddr, Path};
+use super::{EndpointMeta, WeightedAddr, Path};
 use futures::{Future, Stream, Poll};
 use futures::sync::mpsc;
 use tokio_core::reactor::Handle;
@@ -4,7 +4,7 @@
 // balancers can be shared across logical names. In the meantime, it's sufficient to have
 // a balancer per logical name.
 
-use super::{WeightedAddr, Result, Error};
+use super::{EndpointMeta, WeightedAddr, Result, Error};
 use bytes::{Buf, BufMut, IntoBuf, Bytes, BytesMut};
 use futures::{Async, Future, IntoFuture, Poll, Stream};
 use hyper::{Body, Chunk, Client, StatusCode, Uri};
@@ -235,12 +235,23 @@ fn to_weighted_addrs(namerd_addrs: &[NamerdAddr]) -> Vec<WeightedAddr> {
     for na in namerd_addrs {
         let addr = net::SocketAddr::new(na.ip.parse().unwrap(), na.port);
         let w = na.meta.endpoint_addr_weight.unwrap_or(1.0);
+        if !w.is_finite() || w < 0.0 {
+            warn!("ignoring {} with invalid weight: {}", addr, w);
+            continue;
+        }
         sum += w;
-        dsts.push(WeightedAddr::new(addr, w));
+        let meta = EndpointMeta {
+            authority: na.meta.authority.clone(),
+            node_name: na.meta.node_name.clone(),
+        };
+        dsts.push(WeightedAddr::new(addr, w, meta));
     }
-    // Normalize weights on [0.0, 0.1].
-    for dst in &mut dsts {
-        dst.weight /= sum;
+    // Normalize weights on [0.0, 1.0]. Endpoints with a weight of zero are kept, so that
+    // existing connections are not affected, but receive no new connections.
+    if sum > 0.0 {
+        for dst in &mut dsts {
+            dst.weight /= sum;
+        }
     }
     dsts
 }
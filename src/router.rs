@@ -1,5 +1,6 @@
 use super::{Path, connector};
 use super::balancer::{Balancer, BalancerFactory};
+use super::connector::ConnectorFactory;
 use super::resolver::Resolver;
 use futures::{Future, Poll, Async};
 use std::cell::RefCell;
@@ -12,6 +13,7 @@ use tokio_timer::Timer;
 
 static ROUTE_CREATE_KEY: &'static str = "route_create";
 static ROUTE_ERROR_KEY: &'static str = "route_error";
+static ROUTE_EVICT_KEY: &'static str = "route_evict";
 static ROUTE_FOUND_KEY: &'static str = "route_found";
 static ROUTE_TIME_US_KEY: &'static str = "route_time_us";
 
@@ -22,6 +24,7 @@ pub fn new(resolver: Resolver, factory: BalancerFactory, metrics: &tacho::Scope)
         routes: HashMap::default(),
         route_create: metrics.counter(ROUTE_CREATE_KEY),
         route_error: metrics.counter(ROUTE_ERROR_KEY),
+        route_evict: metrics.counter(ROUTE_EVICT_KEY),
         route_found: metrics.counter(ROUTE_FOUND_KEY),
         route_time_us: metrics.stat(ROUTE_TIME_US_KEY),
     };
@@ -40,6 +43,34 @@ impl Router {
     pub fn route(&self, dst: &Path, rct: &Handle, tim: &Timer) -> Route {
         self.0.borrow_mut().route(dst, rct, tim)
     }
+
+    /// Replaces the router's connector configuration.
+    ///
+    /// Cached routes whose connector configuration has changed are evicted so that their
+    /// balancers are rebuilt when they are next used. All other routes, and all
+    /// established streams, are unaffected.
+    ///
+    /// Returns the number of routes that were evicted.
+    pub fn update_connector_factory(&self, cf: ConnectorFactory) -> usize {
+        self.0.borrow_mut().update_connector_factory(cf)
+    }
+
+    /// Replaces the configurations of a dynamic client.
+    ///
+    /// Cached routes are kept, and their balancers use the new configuration for new
+    /// connections. Returns the number of routes that were updated.
+    pub fn update_connector_configs(
+        &self,
+        configs: &[connector::ConnectorConfig],
+    ) -> Result<usize, connector::ConfigError> {
+        self.0.borrow().factory.update_connector_configs(configs)
+    }
+
+    /// Returns a task that reloads the client configuration from a watched file, if one
+    /// is configured.
+    pub fn watch_client_config(&self, timer: &Timer) -> Option<Box<Future<Item = (), Error = ()>>> {
+        self.0.borrow().factory.watch(timer)
+    }
 }
 
 struct InnerRouter {
@@ -48,11 +79,35 @@ struct InnerRouter {
     factory: BalancerFactory,
     route_create: tacho::Counter,
     route_error: tacho::Counter,
+    route_evict: tacho::Counter,
     route_found: tacho::Counter,
     route_time_us: tacho::Stat,
 }
 
 impl InnerRouter {
+    fn update_connector_factory(&mut self, cf: ConnectorFactory) -> usize {
+        let n_routes = self.routes.len();
+        let changed = {
+            let factory = &self.factory;
+            self.routes
+                .keys()
+                .filter(|dst| factory.connector_config(dst) != cf.connector_config(dst))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        if self.factory.set_connector_factory(cf) {
+            for dst in changed {
+                self.routes.remove(&dst);
+            }
+        } else {
+            // Existing balancers cannot receive updates from the new factory.
+            self.routes.clear();
+        }
+        let evicted = n_routes - self.routes.len();
+        self.route_evict.incr(evicted);
+        evicted
+    }
+
     fn route(&mut self, dst: &Path, reactor: &Handle, timer: &Timer) -> Route {
         let t = tacho::Timing::start();
         let r = self.do_route(dst, reactor, timer);
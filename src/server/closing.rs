@@ -0,0 +1,51 @@
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Tells a server to stop accepting connections and to finish serving its active
+/// streams by a deadline.
+///
+/// A `Closing` may be cloned so that it may be triggered from outside of the server's
+/// task (i.e. when the process is shutting down).
+#[derive(Clone, Default)]
+pub struct Closing(Rc<RefCell<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    deadline: Option<Instant>,
+    tasks: Vec<Task>,
+}
+
+impl Closing {
+    /// Closes the server by the given deadline.
+    ///
+    /// If the server is already closing, the earlier of the two deadlines is used.
+    pub fn close(&self, deadline: Instant) {
+        let mut inner = self.0.borrow_mut();
+        let deadline = match inner.deadline {
+            Some(d) if d < deadline => d,
+            _ => deadline,
+        };
+        inner.deadline = Some(deadline);
+        for t in inner.tasks.drain(..) {
+            t.notify();
+        }
+    }
+
+    /// Indicates whether the server has been told to close.
+    pub fn is_closing(&self) -> bool {
+        self.0.borrow().deadline.is_some()
+    }
+
+    /// Returns the deadline by which the server must close, if it is closing.
+    ///
+    /// The current task is notified whenever the deadline changes.
+    pub fn poll_deadline(&self) -> Option<Instant> {
+        let mut inner = self.0.borrow_mut();
+        if !inner.tasks.iter().any(|t| t.will_notify_current()) {
+            inner.tasks.push(task::current());
+        }
+        inner.deadline
+    }
+}
@@ -1,4 +1,7 @@
 use super::{Unbound, sni};
+use super::http_connect::HttpConnect;
+use super::ingress::{DstTemplate, Ingress};
+use super::socks::Socks5;
 use super::super::router::Router;
 use rustls;
 use std::cell::RefCell;
@@ -14,10 +17,12 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     NoDstName,
+    DstNameWithIngress,
+    InvalidDstName(String),
     Sni(sni::Error),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct ServerConfig {
     port: u16,
@@ -27,10 +32,16 @@ pub struct ServerConfig {
     connect_timeout_ms: Option<u64>,
     connection_lifetime_secs: Option<u64>,
     max_concurrency: Option<usize>,
+    ingress: Option<IngressConfig>,
     // TODO idle time
 }
 
 impl ServerConfig {
+    pub fn listen_addr(&self) -> net::SocketAddr {
+        let ip = self.ip.unwrap_or_else(|| net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)));
+        net::SocketAddr::new(ip, self.port)
+    }
+
     pub fn mk_server(
         &self,
         router: Router,
@@ -39,20 +50,27 @@ impl ServerConfig {
     ) -> Result<Unbound> {
         match *self {
             ServerConfig {
-                port,
-                ref ip,
+                port: _,
+                ip: _,
                 ref dst_name,
                 ref tls,
                 ref connect_timeout_ms,
                 ref connection_lifetime_secs,
                 ref max_concurrency,
+                ref ingress,
             } => {
-                if dst_name.is_none() {
-                    return Err(Error::NoDstName);
-                }
-                let dst_name = dst_name.as_ref().unwrap().clone();
-                let ip = ip.unwrap_or_else(|| net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)));
-                let addr = net::SocketAddr::new(ip, port);
+                let ingress = match (dst_name.as_ref(), ingress.as_ref()) {
+                    (None, None) => return Err(Error::NoDstName),
+                    (Some(_), Some(_)) => return Err(Error::DstNameWithIngress),
+                    (Some(n), None) => {
+                        if !n.starts_with('/') {
+                            return Err(Error::InvalidDstName(n.clone()));
+                        }
+                        Ingress::Static(n.clone().into())
+                    }
+                    (None, Some(i)) => i.mk_ingress()?,
+                };
+                let addr = self.listen_addr();
                 let tls = match tls.as_ref() {
                     None => None,
                     Some(&TlsServerConfig {
@@ -74,7 +92,7 @@ impl ServerConfig {
                 let max_concurrency = max_concurrency.unwrap_or(super::DEFAULT_MAX_CONCURRENCY);
                 Ok(super::unbound(
                     addr,
-                    dst_name.into(),
+                    ingress,
                     router,
                     buf,
                     tls,
@@ -88,10 +106,70 @@ impl ServerConfig {
     }
 }
 
+/// Configures servers that act as proxies, reading each connection's destination from
+/// the client.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, tag = "kind")]
+pub enum IngressConfig {
+    #[serde(rename = "io.l5d.socks5")]
+    Socks5(Socks5Config),
+
+    #[serde(rename = "io.l5d.httpConnect")]
+    HttpConnect(HttpConnectConfig),
+}
+
+impl IngressConfig {
+    fn mk_ingress(&self) -> Result<Ingress> {
+        match *self {
+            IngressConfig::Socks5(ref config) => {
+                let dst_name = mk_dst_template(&config.dst_name)?;
+                let users = config.users.as_ref().map(|users| {
+                    users
+                        .iter()
+                        .map(|u| (u.username.clone(), u.password.clone()))
+                        .collect()
+                });
+                Ok(Ingress::Socks5(Socks5::new(dst_name, users)))
+            }
+            IngressConfig::HttpConnect(ref config) => {
+                let dst_name = mk_dst_template(&config.dst_name)?;
+                Ok(Ingress::HttpConnect(HttpConnect::new(dst_name)))
+            }
+        }
+    }
+}
+
+fn mk_dst_template(dst_name: &str) -> Result<DstTemplate> {
+    DstTemplate::new(dst_name).ok_or_else(|| Error::InvalidDstName(dst_name.to_owned()))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Socks5Config {
+    /// Names the destination of each request, substituting `{host}` and `{port}`.
+    pub dst_name: String,
+    /// If set, clients must authenticate with one of these credentials.
+    pub users: Option<Vec<Socks5UserConfig>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HttpConnectConfig {
+    /// Names the destination of each request, substituting `{host}` and `{port}`.
+    pub dst_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Socks5UserConfig {
+    pub username: String,
+    pub password: String,
+}
+
 // TODO support cypher suites
 // TODO support client validation
 // TODO supoprt persistence?
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsServerConfig {
     pub alpn_protocols: Option<Vec<String>>,
@@ -99,7 +177,7 @@ pub struct TlsServerConfig {
     pub identities: Option<HashMap<String, TlsServerIdentityConfig>>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct TlsServerIdentityConfig {
     pub certs: Vec<String>,
@@ -0,0 +1,168 @@
+//! Accepts HTTP/1.1 CONNECT requests, as sent by clients configured to use an HTTP
+//! proxy.
+
+use super::ingress::DstTemplate;
+use super::super::Path;
+use super::super::connection::Socket;
+use super::super::connection::head::read_head;
+use futures::{Future, future};
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_io::io::write_all;
+
+/// Limits the size of a request head so that clients cannot consume unbounded memory.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+const ESTABLISHED: &'static [u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Reads a destination from an HTTP CONNECT request.
+#[derive(Clone)]
+pub struct HttpConnect(Rc<DstTemplate>);
+
+impl HttpConnect {
+    pub fn new(dst_name: DstTemplate) -> HttpConnect {
+        HttpConnect(Rc::new(dst_name))
+    }
+
+    pub fn dst_name(&self) -> &DstTemplate {
+        &self.0
+    }
+
+    /// Reads a CONNECT request from the client.
+    ///
+    /// If the request cannot be served, an error response is written before failing.
+    pub fn accept(&self, socket: Socket) -> IoFuture<(Socket, Path)> {
+        let dst_name = self.0.clone();
+        let read = read_head(socket, MAX_HEAD_BYTES);
+        let f = read.and_then(move |(mut socket, head, rest)| -> IoFuture<(Socket, Path)> {
+            match parse_request(&head, &dst_name) {
+                Ok(dst) => {
+                    // Clients may send data before the tunnel is established.
+                    socket.unread(rest);
+                    Box::new(future::ok((socket, dst)))
+                }
+                Err((status, msg)) => {
+                    let f = write_all(socket, response(status)).then(move |_| {
+                        Err::<(Socket, Path), io::Error>(
+                            io::Error::new(io::ErrorKind::InvalidData, msg),
+                        )
+                    });
+                    Box::new(f)
+                }
+            }
+        });
+        Box::new(f)
+    }
+}
+
+/// Tells the client whether a connection to its destination was established.
+pub fn reply(socket: Socket, result: Result<SocketAddr, &io::Error>) -> IoFuture<Socket> {
+    let rsp = match result {
+        Ok(_) => ESTABLISHED.to_vec(),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => response("504 Gateway Timeout"),
+        Err(_) => response("502 Bad Gateway"),
+    };
+    Box::new(write_all(socket, rsp).map(|(socket, _)| socket))
+}
+
+fn response(status: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        status
+    ).into_bytes()
+}
+
+/// Determines the destination name of a CONNECT request, or the status with which the
+/// request should be rejected.
+fn parse_request(head: &[u8], dst_name: &DstTemplate) -> Result<Path, (&'static str, String)> {
+    if !head.ends_with(b"\r\n\r\n") {
+        return Err(("431 Request Header Fields Too Large", "request too large".into()));
+    }
+    let head = ::std::str::from_utf8(head).map_err(|_| {
+        ("400 Bad Request", "request is not UTF-8".to_owned())
+    })?;
+    let line = head.lines().next().unwrap_or("");
+    let mut parts = line.split(' ');
+    let (method, authority, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(a), Some(v)) if parts.next().is_none() => (m, a, v),
+        _ => return Err(("400 Bad Request", format!("invalid request line: {}", line))),
+    };
+    if !version.starts_with("HTTP/1.") {
+        let msg = format!("unsupported version: {}", version);
+        return Err(("505 HTTP Version Not Supported", msg));
+    }
+    if method != "CONNECT" {
+        return Err(("405 Method Not Allowed", format!("unsupported method: {}", method)));
+    }
+
+    let invalid = || ("400 Bad Request", format!("invalid authority: {}", authority));
+    let (host, port) = if authority.starts_with('[') {
+        // An IPv6 address, i.e. `[::1]:443`.
+        let end = authority.find("]:").ok_or_else(&invalid)?;
+        (&authority[1..end], &authority[end + 2..])
+    } else {
+        let mut parts = authority.rsplitn(2, ':');
+        let port = parts.next().ok_or_else(&invalid)?;
+        let host = parts.next().ok_or_else(&invalid)?;
+        (host, port)
+    };
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    dst_name.render(host, port).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_request;
+    use super::super::ingress::DstTemplate;
+    use super::super::super::Path;
+
+    fn parse(head: &str) -> Result<Path, &'static str> {
+        let template = DstTemplate::new("/svc/{host}/{port}").unwrap();
+        parse_request(head.as_bytes(), &template).map_err(|(status, _)| status)
+    }
+
+    #[test]
+    fn connect_to_name() {
+        let dst = parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(dst, Ok(Path::from("/svc/example.com/443")));
+    }
+
+    #[test]
+    fn connect_to_ipv6() {
+        assert_eq!(parse("CONNECT [::1]:443 HTTP/1.1\r\n\r\n"), Ok(Path::from("/svc/::1/443")));
+        assert_eq!(parse("CONNECT [::1] HTTP/1.1\r\n\r\n"), Err("400 Bad Request"));
+    }
+
+    #[test]
+    fn missing_port() {
+        assert_eq!(parse("CONNECT example.com HTTP/1.1\r\n\r\n"), Err("400 Bad Request"));
+        assert_eq!(parse("CONNECT example.com: HTTP/1.1\r\n\r\n"), Err("400 Bad Request"));
+    }
+
+    #[test]
+    fn invalid_request_line() {
+        assert_eq!(parse("CONNECT example.com:443\r\n\r\n"), Err("400 Bad Request"));
+        assert_eq!(parse("CONNECT a:1 HTTP/1.1 x\r\n\r\n"), Err("400 Bad Request"));
+        assert_eq!(parse("CONNECT a/b:443 HTTP/1.1\r\n\r\n"), Err("400 Bad Request"));
+    }
+
+    #[test]
+    fn unsupported_method() {
+        assert_eq!(parse("GET / HTTP/1.1\r\n\r\n"), Err("405 Method Not Allowed"));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let status = parse("CONNECT example.com:443 HTTP/2.0\r\n\r\n");
+        assert_eq!(status, Err("505 HTTP Version Not Supported"));
+    }
+
+    #[test]
+    fn incomplete_head() {
+        let status = parse("CONNECT example.com:443 HTTP/1.1\r\n");
+        assert_eq!(status, Err("431 Request Header Fields Too Large"));
+    }
+}
@@ -0,0 +1,195 @@
+use super::{SrcCtx, http_connect, socks, timeout};
+use super::super::Path;
+use super::super::balancer::{EndpointConnection, SrcMeta};
+use super::super::connection::{Connection, Socket};
+use super::super::router::Router;
+use futures::Future;
+use std::{fmt, io, net};
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+/// Determines the destination of each accepted connection.
+#[derive(Clone)]
+pub enum Ingress {
+    /// Routes all connections to a single destination.
+    Static(Path),
+
+    /// Reads each connection's destination from a SOCKS5 CONNECT request.
+    Socks5(socks::Socks5),
+
+    /// Reads each connection's destination from an HTTP CONNECT request.
+    HttpConnect(http_connect::HttpConnect),
+}
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+impl Ingress {
+    /// Obtains an outbound connection for an accepted connection.
+    ///
+    /// For proxy protocols, the connect timeout applies separately to reading the
+    /// client's request and to establishing the outbound connection, so that the client
+    /// can be told why its request failed.
+    pub fn connect<S>(
+        &self,
+        src: S,
+        router: &Router,
+        reactor: &Handle,
+        timer: &Timer,
+        connect_timeout: Option<Duration>,
+    ) -> IoFuture<(Connection<SrcCtx>, EndpointConnection)>
+    where
+        S: Future<Item = Connection<SrcCtx>, Error = io::Error> + 'static,
+    {
+        let router = router.clone();
+        let reactor = reactor.clone();
+        let timer = timer.clone();
+
+        if let Ingress::Static(ref dst_name) = *self {
+            // Obtain a balancer while the incoming connection is being established. We
+            // obtain an outbound connection after the incoming handshake is complete so
+            // that we don't waste outbound connections on failed inbound connections.
+            let balancer = router.route(dst_name, &reactor, &timer);
+            let connect = src.join(balancer).and_then(move |(src, b)| {
+                b.connect(src_meta(&src)).map(move |dst| (src, dst))
+            });
+            return timeout(connect, connect_timeout, &timer);
+        }
+
+        let accept = {
+            let ingress = self.clone();
+            src.and_then(move |src| {
+                let Connection { socket, ctx } = src;
+                ingress.accept(socket).map(move |(socket, dst_name)| {
+                    (Connection::new(socket, ctx), dst_name)
+                })
+            })
+        };
+
+        let ingress = self.clone();
+        let connect = timeout(accept, connect_timeout, &timer).and_then(
+            move |(src, dst_name)| {
+                let meta = src_meta(&src);
+                let connect = router.route(&dst_name, &reactor, &timer).and_then(
+                    move |b| b.connect(meta),
+                );
+                timeout(connect, connect_timeout, &timer).then(move |res| {
+                    let Connection { socket, ctx } = src;
+                    let reply = ingress.reply(socket, res.as_ref().map(|dst| dst.local_addr()));
+                    reply.then(move |reply| match (reply, res) {
+                        (Ok(socket), Ok(dst)) => Ok((Connection::new(socket, ctx), dst)),
+                        (_, Err(e)) |
+                        (Err(e), Ok(_)) => Err(e),
+                    })
+                })
+            },
+        );
+        Box::new(connect)
+    }
+
+    /// Reads the destination requested by a proxy client.
+    fn accept(&self, socket: Socket) -> IoFuture<(Socket, Path)> {
+        match *self {
+            Ingress::Static(_) => unreachable!("static destinations are not requested"),
+            Ingress::Socks5(ref socks) => socks.accept(socket),
+            Ingress::HttpConnect(ref http) => http.accept(socket),
+        }
+    }
+
+    /// Tells a proxy client whether its destination could be reached.
+    fn reply(&self, socket: Socket, result: Result<net::SocketAddr, &io::Error>) -> IoFuture<Socket> {
+        match *self {
+            Ingress::Static(_) => unreachable!("static destinations are not requested"),
+            Ingress::Socks5(_) => socks::reply(socket, result),
+            Ingress::HttpConnect(_) => http_connect::reply(socket, result),
+        }
+    }
+}
+
+/// Describes an inbound connection to its balancer.
+fn src_meta(src: &Connection<SrcCtx>) -> SrcMeta {
+    SrcMeta {
+        addr: Some(src.peer_addr()),
+        sni: src.socket.sni_hostname().map(|s| s.to_owned()),
+    }
+}
+
+impl fmt::Display for Ingress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ingress::Static(ref dst_name) => write!(f, "{}", dst_name),
+            Ingress::Socks5(ref socks) => write!(f, "socks5 {}", socks.dst_name()),
+            Ingress::HttpConnect(ref http) => write!(f, "http connect {}", http.dst_name()),
+        }
+    }
+}
+
+/// Builds a destination name from a template such as `/svc/{host}/{port}`.
+#[derive(Clone, Debug)]
+pub struct DstTemplate(String);
+
+impl DstTemplate {
+    /// Parses a template, which must be an absolute path.
+    pub fn new(template: &str) -> Option<DstTemplate> {
+        if template.starts_with('/') {
+            Some(DstTemplate(template.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    /// Substitutes `{host}` and `{port}` into the template.
+    ///
+    /// Hosts must be DNS names or IP addresses so that a client cannot address arbitrary
+    /// names.
+    pub fn render(&self, host: &str, port: u16) -> Option<Path> {
+        let valid = !host.is_empty() &&
+            host.chars().all(|c| {
+                c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' || c == ':'
+            });
+        if !valid {
+            return None;
+        }
+        let name = self.0.replace("{host}", host).replace(
+            "{port}",
+            &port.to_string(),
+        );
+        Some(name.into())
+    }
+}
+
+impl fmt::Display for DstTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DstTemplate;
+    use super::super::super::Path;
+
+    fn render(host: &str, port: u16) -> Option<Path> {
+        DstTemplate::new("/svc/{host}/{port}").unwrap().render(host, port)
+    }
+
+    #[test]
+    fn template_must_be_absolute() {
+        assert!(DstTemplate::new("svc/{host}").is_none());
+    }
+
+    #[test]
+    fn render_names_and_addresses() {
+        assert_eq!(render("example.com", 443), Some(Path::from("/svc/example.com/443")));
+        assert_eq!(render("my_svc-1.local", 80), Some(Path::from("/svc/my_svc-1.local/80")));
+        assert_eq!(render("10.0.0.1", 80), Some(Path::from("/svc/10.0.0.1/80")));
+        assert_eq!(render("::1", 80), Some(Path::from("/svc/::1/80")));
+    }
+
+    #[test]
+    fn render_rejects_hostile_hosts() {
+        for host in &["", "a/b", "../x", "a b", "a\r\nb", "{port}", "a%2f", "é.example.com"] {
+            assert_eq!(render(host, 80), None, "{:?} must be rejected", host);
+        }
+    }
+}
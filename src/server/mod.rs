@@ -1,30 +1,39 @@
-//! TODO `dst_name` should be chosen dynamically.
-
-use super::Path;
 use super::connection::{Connection, Socket, ctx, secure, socket};
 use super::router::Router;
 use futures::{Async, Future, Poll, Stream, future};
+use futures::stream::FuturesUnordered;
 use rustls;
 use std::{io, net};
 use std::cell::RefCell;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tacho;
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor::Handle;
-use tokio_timer::Timer;
+use tokio_timer::{Sleep, Timer};
 
+mod closing;
 mod config;
+mod http_connect;
+mod ingress;
 mod sni;
+mod socks;
+pub use self::closing::Closing;
 pub use self::config::{Error as ConfigError, ServerConfig};
+use self::ingress::Ingress;
 
 const DEFAULT_MAX_CONCURRENCY: usize = 100000;
 
+/// Limits how often accepting is retried after it fails, i.e. because the process has
+/// run out of file descriptors.
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
 /// Builds a server that is not yet bound on a port.
 fn unbound(
     listen_addr: net::SocketAddr,
-    dst_name: Path,
+    ingress: Ingress,
     router: Router,
     buf: Rc<RefCell<Vec<u8>>>,
     tls: Option<UnboundTls>,
@@ -36,7 +45,7 @@ fn unbound(
     let metrics = metrics.clone().prefixed("srv");
     Unbound {
         listen_addr,
-        dst_name,
+        ingress,
         router,
         buf,
         tls,
@@ -49,7 +58,7 @@ fn unbound(
 
 pub struct Unbound {
     listen_addr: net::SocketAddr,
-    dst_name: Path,
+    ingress: Ingress,
     router: Router,
     buf: Rc<RefCell<Vec<u8>>>,
     tls: Option<UnboundTls>,
@@ -63,10 +72,6 @@ impl Unbound {
         self.listen_addr
     }
 
-    pub fn dst_name(&self) -> &Path {
-        &self.dst_name
-    }
-
     fn init_src_connection(
         src_tcp: TcpStream,
         metrics: &Metrics,
@@ -97,9 +102,31 @@ impl Unbound {
     }
 
     pub fn bind(self, reactor: &Handle, timer: &Timer) -> io::Result<Bound> {
-        debug!("routing on {} to {}", self.listen_addr, self.dst_name);
+        debug!("routing on {} to {}", self.listen_addr, self.ingress);
         let listen = TcpListener::bind(&self.listen_addr, reactor)?;
+        self.serve(listen, reactor, timer)
+    }
+
+    /// Serves on a listening socket that was bound by another process.
+    pub fn bind_inherited(
+        self,
+        listener: net::TcpListener,
+        reactor: &Handle,
+        timer: &Timer,
+    ) -> io::Result<Bound> {
+        debug!(
+            "routing on inherited {} to {}",
+            self.listen_addr,
+            self.ingress
+        );
+        listener.set_nonblocking(true)?;
+        let listen = TcpListener::from_listener(listener, &self.listen_addr, reactor)?;
+        self.serve(listen, reactor, timer)
+    }
+
+    fn serve(self, listen: TcpListener, reactor: &Handle, timer: &Timer) -> io::Result<Bound> {
         let bound_addr = listen.local_addr().unwrap();
+        let listen_fd = listen.as_raw_fd();
 
         let metrics = self.metrics.labeled("srv_addr", format!("{}", bound_addr));
         let tls = self.tls.map(|tls| {
@@ -130,8 +157,7 @@ impl Unbound {
             per_conn,
         };
 
-        // TODO determine dst_addr dynamically.
-        let dst_name = self.dst_name;
+        let ingress = self.ingress;
         let router = self.router;
         let connect_timeout = self.connect_timeout;
         let connection_lifetime = self.connection_lifetime;
@@ -139,9 +165,9 @@ impl Unbound {
 
         let reactor = reactor.clone();
         let timer = timer.clone();
-        let serving = listen
-            .incoming()
-            .map(move |(src_tcp, src_addr)| {
+        let incoming = {
+            let timer = timer.clone();
+            listen.incoming().map(move |(src_tcp, src_addr)| -> Serving {
                 trace!("received incoming connection from {}", src_addr);
                 metrics.accepts.incr(1);
                 let active = metrics.active.clone();
@@ -150,29 +176,18 @@ impl Unbound {
                 waiters.incr(1);
 
                 // Finish accepting the connection from the server.
-                // TODO determine dst_addr dynamically.
                 let src = Unbound::init_src_connection(src_tcp, &metrics, &tls);
 
-                // Obtain a balancing endpoint selector for the given destination.
-                let balancer = router.route(&dst_name, &reactor, &timer);
+                // Once the incoming connection is ready and its destination is known, obtain
+                // an outbound connection from the destination's balancer. The connection
+                // timeout is enforced by the ingress.
+                let connect =
+                    ingress.connect(src, &router, &reactor, &timer, connect_timeout);
 
-                // Once the incoming connection is ready and we have a balancer ready, obtain an
-                // outbound connection and begin streaming. We obtain an outbound connection after
-                // the incoming handshake is complete so that we don't waste outbound connections
-                // on failed inbound connections.
-                let connect = src.join(balancer).and_then(move |(src, b)| {
-                    b.connect().map(move |dst| (src, dst))
-                });
-
-                // Enforce a connection timeout, measure successful connection
-                // latencies and failure counts.
+                // Measure successful connection latencies and failure counts.
                 let connect = {
                     // Measure the time until the connection is established, if it completes.
-                    let c = timeout(
-                        metrics.per_conn.latency.time(connect),
-                        connect_timeout,
-                        &timer,
-                    );
+                    let c = metrics.per_conn.latency.time(connect);
                     let fails = metrics.connect_failures.clone();
                     c.then(move |res| match res {
                         Ok((src, dst)) => {
@@ -218,7 +233,7 @@ impl Unbound {
 
                 let closes = metrics.closes.clone();
                 let failures = metrics.failures.clone();
-                stream.then(move |ret| {
+                Box::new(stream.then(move |ret| -> Result<(), ()> {
                     active.decr(1);
                     if ret.is_ok() {
                         closes.incr(1);
@@ -226,27 +241,207 @@ impl Unbound {
                         failures.incr(1);
                     }
                     Ok(())
-                })
+                }))
             })
-            .buffer_unordered(self.max_concurrency);
+        };
 
-        Ok(Bound(Box::new(serving)))
+        Ok(Bound {
+            listen_addr: bound_addr,
+            listen_fd,
+            incoming: Some(Box::new(incoming)),
+            active: FuturesUnordered::new(),
+            accept_backoff: None,
+            max_concurrency: self.max_concurrency,
+            closing: Closing::default(),
+            deadline: None,
+            timer,
+        })
+    }
+}
+
+/// Serves a single connection from accept until its stream completes.
+type Serving = Box<Future<Item = (), Error = ()>>;
+
+/// A server that accepts connections and serves streams until it is closed.
+///
+/// Once the server's `Closing` has been triggered, no further connections are accepted.
+/// The server completes when all of its active streams complete or when the closing
+/// deadline passes, at which point all remaining streams are dropped.
+pub struct Bound {
+    listen_addr: net::SocketAddr,
+    listen_fd: RawFd,
+    incoming: Option<Box<Stream<Item = Serving, Error = io::Error>>>,
+    active: FuturesUnordered<Serving>,
+    /// Fires when accepting may resume after a failed accept.
+    accept_backoff: Option<Sleep>,
+    max_concurrency: usize,
+    closing: Closing,
+    deadline: Option<(Instant, Sleep)>,
+    timer: Timer,
+}
+
+impl Bound {
+    pub fn listen_addr(&self) -> net::SocketAddr {
+        self.listen_addr
+    }
+
+    /// Returns a handle that may be used to close this server.
+    pub fn closing(&self) -> Closing {
+        self.closing.clone()
+    }
+
+    /// Accepts new connections until the server is at capacity or no connections are
+    /// ready.
+    ///
+    /// Returns true iff accepting stopped because the server is at capacity.
+    fn accept(&mut self) -> bool {
+        if !self.accept_backoff_elapsed() {
+            return false;
+        }
+        if let Some(mut incoming) = self.incoming.take() {
+            loop {
+                if self.active.len() == self.max_concurrency {
+                    self.incoming = Some(incoming);
+                    return true;
+                }
+                match incoming.poll() {
+                    Ok(Async::NotReady) => {
+                        self.incoming = Some(incoming);
+                        return false;
+                    }
+                    Ok(Async::Ready(None)) => {
+                        debug!("{}: no longer accepting connections", self.listen_addr);
+                        return false;
+                    }
+                    Ok(Async::Ready(Some(serving))) => self.active.push(serving),
+                    Err(e) => {
+                        // Errors such as EMFILE persist until resources are released, so
+                        // accepting is paused rather than retried immediately.
+                        error!("{}: failed to accept connection: {}", self.listen_addr, e);
+                        self.incoming = Some(incoming);
+                        let backoff = Duration::from_millis(ACCEPT_BACKOFF_MS);
+                        self.accept_backoff = Some(self.timer.sleep(backoff));
+                        self.accept_backoff_elapsed();
+                        return false;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Indicates whether accepting may resume after a failed accept, scheduling this
+    /// task to be notified when it may.
+    fn accept_backoff_elapsed(&mut self) -> bool {
+        match self.accept_backoff.as_mut().map(|sleep| sleep.poll()) {
+            None => true,
+            Some(Ok(Async::NotReady)) => false,
+            Some(Ok(Async::Ready(()))) |
+            Some(Err(_)) => {
+                self.accept_backoff = None;
+                true
+            }
+        }
+    }
+
+    /// Drives all active streams, returning the number of streams that completed.
+    fn poll_active(&mut self) -> usize {
+        let mut completed = 0;
+        loop {
+            match self.active.poll() {
+                Ok(Async::NotReady) |
+                Ok(Async::Ready(None)) => return completed,
+                Ok(Async::Ready(Some(()))) |
+                Err(()) => completed += 1,
+            }
+        }
+    }
+
+    /// Stops accepting connections once the server has been closed and tracks the
+    /// deadline by which active streams must complete.
+    fn poll_closing(&mut self) {
+        if let Some(deadline) = self.closing.poll_deadline() {
+            if self.incoming.take().is_some() {
+                info!(
+                    "{}: closing; draining {} active streams",
+                    self.listen_addr,
+                    self.active.len()
+                );
+            }
+            let changed = self.deadline.as_ref().map(|&(d, _)| d != deadline).unwrap_or(
+                true,
+            );
+            if changed {
+                let now = Instant::now();
+                let wait = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                self.deadline = Some((deadline, self.timer.sleep(wait)));
+            }
+        }
+    }
+
+    fn deadline_expired(&mut self) -> bool {
+        match self.deadline {
+            None => false,
+            Some((deadline, ref mut sleep)) => {
+                if deadline <= Instant::now() {
+                    return true;
+                }
+                match sleep.poll() {
+                    Ok(Async::NotReady) => false,
+                    Ok(Async::Ready(())) => true,
+                    Err(e) => {
+                        warn!("{}: failed to wait for close deadline: {}", self.listen_addr, e);
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for Bound {
+    /// The listening socket's file descriptor.
+    fn as_raw_fd(&self) -> RawFd {
+        self.listen_fd
     }
 }
 
-pub struct Bound(Box<Stream<Item = (), Error = io::Error> + 'static>);
 impl Future for Bound {
     type Item = ();
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_closing();
+
         loop {
-            match self.0.poll() {
-                Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
-                Ok(Async::Ready(Some(_))) |
-                Err(_) => {}
+            let at_capacity = self.accept();
+            let completed = self.poll_active();
+            // If streams completed while the server was at capacity, there may be
+            // connections waiting to be accepted.
+            if !at_capacity || completed == 0 {
+                break;
             }
         }
+
+        if self.incoming.is_none() {
+            if self.active.is_empty() {
+                debug!("{}: closed", self.listen_addr);
+                return Ok(Async::Ready(()));
+            }
+            if self.deadline_expired() {
+                info!(
+                    "{}: close deadline passed; dropping {} active streams",
+                    self.listen_addr,
+                    self.active.len()
+                );
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        Ok(Async::NotReady)
     }
 }
 
@@ -0,0 +1,447 @@
+//! Accepts SOCKS5 CONNECT requests (RFC 1928), optionally authenticated with a username
+//! and password (RFC 1929).
+
+use super::ingress::DstTemplate;
+use super::super::Path;
+use super::super::connection::Socket;
+use futures::{Future, future};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all};
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const AUTH_SUCCEEDED: u8 = 0x00;
+const AUTH_FAILED: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Reads a destination from a SOCKS5 CONNECT request.
+#[derive(Clone)]
+pub struct Socks5(Rc<Inner>);
+
+struct Inner {
+    dst_name: DstTemplate,
+    /// If set, clients must authenticate with one of these usernames and passwords.
+    users: Option<HashMap<String, String>>,
+}
+
+impl Socks5 {
+    pub fn new(dst_name: DstTemplate, users: Option<HashMap<String, String>>) -> Socks5 {
+        Socks5(Rc::new(Inner { dst_name, users }))
+    }
+
+    pub fn dst_name(&self) -> &DstTemplate {
+        &self.0.dst_name
+    }
+
+    /// Negotiates authentication and reads a CONNECT request from the client.
+    ///
+    /// If the request cannot be served, an error reply is written before failing.
+    pub fn accept(&self, socket: Socket) -> IoFuture<(Socket, Path)> {
+        let inner = self.0.clone();
+        let f = negotiate(self.0.clone(), socket).and_then(move |socket| {
+            read_request(inner, socket)
+        });
+        Box::new(f)
+    }
+}
+
+/// Tells the client whether a connection to its destination was established.
+///
+/// On success, the local address of the outbound connection is reported as the bound
+/// address.
+pub fn reply(socket: Socket, result: Result<SocketAddr, &io::Error>) -> IoFuture<Socket> {
+    let msg = match result {
+        Ok(addr) => encode_reply(REP_SUCCEEDED, Some(addr)),
+        Err(e) => encode_reply(reply_code(e), None),
+    };
+    Box::new(write_all(socket, msg).map(|(socket, _)| socket))
+}
+
+/// Maps a connection error to a SOCKS reply code.
+fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        io::ErrorKind::ConnectionRefused => REP_REFUSED,
+        io::ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        _ => REP_FAILURE,
+    }
+}
+
+fn encode_reply(rep: u8, addr: Option<SocketAddr>) -> Vec<u8> {
+    let addr = addr.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0));
+    let mut msg = vec![VERSION, rep, 0x00];
+    match addr {
+        SocketAddr::V4(a) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&a.ip().octets());
+        }
+    }
+    msg.push((addr.port() >> 8) as u8);
+    msg.push(addr.port() as u8);
+    msg
+}
+
+/// Writes an error reply and fails.
+fn fail<S: AsyncWrite + 'static, T: 'static>(socket: S, rep: u8, msg: String) -> IoFuture<T> {
+    let f = write_all(socket, encode_reply(rep, None)).then(move |_| {
+        Err::<T, io::Error>(invalid(msg))
+    });
+    Box::new(f)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Selects an authentication method.
+fn negotiate<S: AsyncRead + AsyncWrite + 'static>(inner: Rc<Inner>, socket: S) -> IoFuture<S> {
+    let f = read_exact(socket, [0u8; 2])
+        .and_then(|(socket, hdr)| -> IoFuture<(S, Vec<u8>)> {
+            if hdr[0] != VERSION {
+                let e = invalid(format!("unsupported SOCKS version: {}", hdr[0]));
+                return Box::new(future::err(e));
+            }
+            Box::new(read_exact(socket, vec![0u8; hdr[1] as usize]))
+        })
+        .and_then(move |(socket, methods)| -> IoFuture<S> {
+            let method = if inner.users.is_some() {
+                METHOD_PASSWORD
+            } else {
+                METHOD_NONE
+            };
+            if !methods.contains(&method) {
+                let f = write_all(socket, [VERSION, METHOD_UNACCEPTABLE]).then(|_| {
+                    Err::<S, io::Error>(invalid("no acceptable authentication method".into()))
+                });
+                return Box::new(f);
+            }
+            let f = write_all(socket, [VERSION, method]).and_then(
+                move |(socket, _)| -> IoFuture<S> {
+                    if method == METHOD_PASSWORD {
+                        authenticate(inner, socket)
+                    } else {
+                        Box::new(future::ok(socket))
+                    }
+                },
+            );
+            Box::new(f)
+        });
+    Box::new(f)
+}
+
+/// Checks the client's username and password.
+fn authenticate<S>(inner: Rc<Inner>, socket: S) -> IoFuture<S>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let f = read_exact(socket, [0u8; 2])
+        .and_then(|(socket, hdr)| -> IoFuture<(S, Vec<u8>)> {
+            if hdr[0] != AUTH_VERSION {
+                let e = invalid(format!("unsupported authentication version: {}", hdr[0]));
+                return Box::new(future::err(e));
+            }
+            // The username is followed by the length of the password.
+            Box::new(read_exact(socket, vec![0u8; hdr[1] as usize + 1]))
+        })
+        .and_then(|(socket, mut username)| {
+            let plen = username.pop().unwrap_or(0) as usize;
+            read_exact(socket, vec![0u8; plen]).map(move |(socket, password)| {
+                (socket, username, password)
+            })
+        })
+        .and_then(move |(socket, username, password)| -> IoFuture<S> {
+            let valid = match inner.users {
+                None => false,
+                Some(ref users) => {
+                    String::from_utf8(username)
+                        .ok()
+                        .and_then(|u| users.get(&u).map(|p| p.as_bytes() == password.as_slice()))
+                        .unwrap_or(false)
+                }
+            };
+            if valid {
+                let f = write_all(socket, [AUTH_VERSION, AUTH_SUCCEEDED]).map(|(s, _)| s);
+                return Box::new(f);
+            }
+            let f = write_all(socket, [AUTH_VERSION, AUTH_FAILED]).then(|_| {
+                let e = io::Error::new(io::ErrorKind::PermissionDenied, "authentication failed");
+                Err::<S, io::Error>(e)
+            });
+            Box::new(f)
+        });
+    Box::new(f)
+}
+
+/// Reads a CONNECT request and determines its destination name.
+fn read_request<S>(inner: Rc<Inner>, socket: S) -> IoFuture<(S, Path)>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let f = read_exact(socket, [0u8; 4])
+        .and_then(|(socket, hdr)| -> IoFuture<(S, String, u16)> {
+            if hdr[0] != VERSION {
+                let e = invalid(format!("unsupported SOCKS version: {}", hdr[0]));
+                return Box::new(future::err(e));
+            }
+            if hdr[1] != CMD_CONNECT {
+                let msg = format!("unsupported command: {}", hdr[1]);
+                return fail(socket, REP_COMMAND_NOT_SUPPORTED, msg);
+            }
+            match hdr[3] {
+                ATYP_IPV4 => {
+                    let f = read_exact(socket, [0u8; 6]).map(|(socket, b)| {
+                        let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+                        (socket, ip.to_string(), port(b[4], b[5]))
+                    });
+                    Box::new(f)
+                }
+                ATYP_IPV6 => {
+                    let f = read_exact(socket, [0u8; 18]).map(|(socket, b)| {
+                        let mut octets = [0u8; 16];
+                        octets.copy_from_slice(&b[..16]);
+                        let ip = Ipv6Addr::from(octets);
+                        (socket, ip.to_string(), port(b[16], b[17]))
+                    });
+                    Box::new(f)
+                }
+                ATYP_DOMAIN => {
+                    let f = read_exact(socket, [0u8; 1])
+                        .and_then(|(socket, len)| {
+                            // The name is followed by the port.
+                            read_exact(socket, vec![0u8; len[0] as usize + 2])
+                        })
+                        .and_then(|(socket, mut b)| {
+                            let lo = b.pop().unwrap_or(0);
+                            let hi = b.pop().unwrap_or(0);
+                            match String::from_utf8(b) {
+                                Ok(host) => Ok((socket, host, port(hi, lo))),
+                                Err(_) => Err(invalid("domain name is not UTF-8".into())),
+                            }
+                        });
+                    Box::new(f)
+                }
+                atyp => {
+                    let msg = format!("unsupported address type: {}", atyp);
+                    fail(socket, REP_ADDRESS_NOT_SUPPORTED, msg)
+                }
+            }
+        })
+        .and_then(move |(socket, host, port)| -> IoFuture<(S, Path)> {
+            match inner.dst_name.render(&host, port) {
+                Some(dst) => Box::new(future::ok((socket, dst))),
+                None => {
+                    let msg = format!("invalid destination: {}:{}", host, port);
+                    fail(socket, REP_NOT_ALLOWED, msg)
+                }
+            }
+        });
+    Box::new(f)
+}
+
+fn port(hi: u8, lo: u8) -> u16 {
+    (u16::from(hi) << 8) | u16::from(lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Inner, negotiate, read_request};
+    use super::super::ingress::DstTemplate;
+    use super::super::super::Path;
+    use futures::{Future, Poll};
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
+    use tokio_io::{AsyncRead, AsyncWrite};
+
+    /// Reads a client's messages and records the server's replies.
+    struct Client {
+        sent: Cursor<Vec<u8>>,
+        received: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.sent.read(buf)
+        }
+    }
+
+    impl AsyncRead for Client {}
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Client {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    /// Runs a handshake with a client that sends `sent`, returning the destination and
+    /// the server's replies.
+    fn accept(users: Option<&[(&str, &str)]>, sent: &[u8]) -> (io::Result<Path>, Vec<u8>) {
+        let users = users.map(|users| {
+            users
+                .iter()
+                .map(|&(u, p)| (u.to_owned(), p.to_owned()))
+                .collect()
+        });
+        let inner = Rc::new(Inner {
+            dst_name: DstTemplate::new("/svc/{host}/{port}").unwrap(),
+            users,
+        });
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let client = Client {
+            sent: Cursor::new(sent.to_vec()),
+            received: received.clone(),
+        };
+        let dst = negotiate(inner.clone(), client)
+            .and_then(move |client| read_request(inner, client))
+            .map(|(_, dst)| dst)
+            .wait();
+        let received = received.borrow().clone();
+        (dst, received)
+    }
+
+    const NO_AUTH: &'static [u8] = &[5, 1, 0];
+
+    const USERS: &'static [(&'static str, &'static str)] = &[("user", "pass")];
+
+    fn request(addr: &[u8]) -> Vec<u8> {
+        let mut msg = NO_AUTH.to_vec();
+        msg.extend_from_slice(&[5, 1, 0]);
+        msg.extend_from_slice(addr);
+        msg
+    }
+
+    #[test]
+    fn connect_ipv4() {
+        let (dst, received) = accept(None, &request(&[1, 10, 0, 0, 1, 0x01, 0xbb]));
+        assert_eq!(dst.unwrap(), Path::from("/svc/10.0.0.1/443"));
+        assert_eq!(received, vec![5, 0]);
+    }
+
+    #[test]
+    fn connect_ipv6() {
+        let mut addr = vec![4];
+        addr.extend_from_slice(&[0; 15]);
+        addr.extend_from_slice(&[1, 0x01, 0xbb]);
+        let (dst, _) = accept(None, &request(&addr));
+        assert_eq!(dst.unwrap(), Path::from("/svc/::1/443"));
+    }
+
+    #[test]
+    fn connect_domain() {
+        let mut addr = vec![3, 11];
+        addr.extend_from_slice(b"example.com");
+        addr.extend_from_slice(&[0, 80]);
+        let (dst, _) = accept(None, &request(&addr));
+        assert_eq!(dst.unwrap(), Path::from("/svc/example.com/80"));
+    }
+
+    #[test]
+    fn hostile_domain_is_not_allowed() {
+        let mut addr = vec![3, 6];
+        addr.extend_from_slice(b"../etc");
+        addr.extend_from_slice(&[0, 80]);
+        let (dst, received) = accept(None, &request(&addr));
+        assert!(dst.is_err());
+        assert_eq!(&received[2..4], &[5, 2]);
+    }
+
+    #[test]
+    fn unsupported_command() {
+        let mut sent = NO_AUTH.to_vec();
+        sent.extend_from_slice(&[5, 2, 0, 1, 10, 0, 0, 1, 0x01, 0xbb]);
+        let (dst, received) = accept(None, &sent);
+        assert!(dst.is_err());
+        assert_eq!(received, vec![5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn unsupported_address_type() {
+        let (dst, received) = accept(None, &request(&[2, 0, 0]));
+        assert!(dst.is_err());
+        assert_eq!(&received[2..4], &[5, 8]);
+    }
+
+    #[test]
+    fn no_acceptable_method() {
+        // The server requires a password but the client offers none.
+        let (dst, received) = accept(Some(USERS), NO_AUTH);
+        assert!(dst.is_err());
+        assert_eq!(received, vec![5, 0xff]);
+
+        let (dst, received) = accept(None, &[5, 1, 2]);
+        assert!(dst.is_err());
+        assert_eq!(received, vec![5, 0xff]);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let (dst, received) = accept(None, &[4, 1, 0]);
+        assert!(dst.is_err());
+        assert!(received.is_empty());
+    }
+
+    fn login(username: &[u8], password: &[u8]) -> Vec<u8> {
+        let mut sent = vec![5, 2, 0, 2, 1, username.len() as u8];
+        sent.extend_from_slice(username);
+        sent.push(password.len() as u8);
+        sent.extend_from_slice(password);
+        sent
+    }
+
+    #[test]
+    fn password_accepted() {
+        let mut sent = login(b"user", b"pass");
+        sent.extend_from_slice(&[5, 1, 0, 1, 10, 0, 0, 1, 0x01, 0xbb]);
+        let (dst, received) = accept(Some(USERS), &sent);
+        assert_eq!(dst.unwrap(), Path::from("/svc/10.0.0.1/443"));
+        assert_eq!(&received[..4], &[5, 2, 1, 0]);
+    }
+
+    #[test]
+    fn password_rejected() {
+        let logins: &[(&[u8], &[u8])] = &[(b"user", b"wrong"), (b"other", b"pass")];
+        for &(username, password) in logins {
+            let (dst, received) = accept(Some(USERS), &login(username, password));
+            assert_eq!(dst.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(received, vec![5, 2, 1, 1]);
+        }
+    }
+}
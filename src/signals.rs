@@ -0,0 +1,62 @@
+//! Handles process signals.
+
+use super::app::Closer;
+use super::reload::Reloader;
+use futures::{Future, Stream};
+use libc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Handle;
+use tokio_signal::unix::Signal;
+use tokio_timer::Timer;
+
+/// Closes the process when it receives SIGTERM or SIGINT.
+///
+/// The first signal initiates a graceful shutdown, giving active streams until `grace`
+/// has elapsed to complete. A subsequent signal closes all streams immediately.
+pub fn close_on_termination(
+    closer: Closer,
+    grace: Duration,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = ()>> {
+    let term = Signal::new(libc::SIGTERM, handle).flatten_stream();
+    let int = Signal::new(libc::SIGINT, handle).flatten_stream();
+
+    let mut closing = false;
+    let f = term.select(int)
+        .for_each(move |sig| {
+            let deadline = if closing {
+                info!("received signal {} while closing; closing immediately", sig);
+                Instant::now()
+            } else {
+                info!("received signal {}; closing gracefully", sig);
+                Instant::now() + grace
+            };
+            closing = true;
+            let _ = closer.unbounded_send(deadline);
+            Ok(())
+        })
+        .map_err(|e| error!("failed to handle signals: {}", e));
+    Box::new(f)
+}
+
+/// Reloads the configuration file when the process receives SIGHUP.
+///
+/// Reloads are applied one at a time. Failures are logged and the previous
+/// configuration continues to run.
+pub fn reload_on_hangup(
+    reloader: Reloader,
+    handle: &Handle,
+    timer: &Timer,
+) -> Box<Future<Item = (), Error = ()>> {
+    let handle = handle.clone();
+    let timer = timer.clone();
+    let f = Signal::new(libc::SIGHUP, &handle)
+        .flatten_stream()
+        .map_err(|e| error!("failed to handle signals: {}", e))
+        .for_each(move |_| {
+            info!("received SIGHUP; reloading configuration");
+            // Errors are logged by the reloader.
+            reloader.reload(&handle, &timer).then(|_| Ok::<(), ()>(()))
+        });
+    Box::new(f)
+}